@@ -23,7 +23,7 @@ use futures_util::sink::SinkExt;
 use futures_util::stream::StreamExt;
 
 use ldap3_proto::proto::*;
-use ldap3_proto::LdapCodec;
+use ldap3_proto::{CodecRole, LdapCodec};
 use openssl::ssl::{Ssl, SslConnector, SslMethod, SslVerifyMode};
 use openssl::x509::X509;
 use std::collections::{BTreeMap, BTreeSet};
@@ -63,6 +63,7 @@ pub enum LdapError {
     FileIOError = -12,
 
     UnavailableCriticalExtension = 12,
+    ConfidentialityRequired = 13,
     InvalidCredentials = 49,
     InsufficentAccessRights = 50,
     EsyncRefreshRequired = 4096,
@@ -75,6 +76,7 @@ impl From<LdapResultCode> for LdapError {
             LdapResultCode::InsufficentAccessRights => LdapError::InsufficentAccessRights,
             LdapResultCode::EsyncRefreshRequired => LdapError::EsyncRefreshRequired,
             LdapResultCode::UnavailableCriticalExtension => LdapError::UnavailableCriticalExtension,
+            LdapResultCode::ConfidentialityRequired => LdapError::ConfidentialityRequired,
             err => {
                 trace!(?err);
                 unimplemented!()
@@ -107,6 +109,7 @@ impl fmt::Display for LdapError {
                 write!(f, "An error occured writing to the transport")
             }
             LdapError::UnavailableCriticalExtension => write!(f, "An extension marked as critical was not available"),
+            LdapError::ConfidentialityRequired => write!(f, "The server requires TLS. Use StartTLS and retry"),
             LdapError::InvalidCredentials => write!(f, "Invalid DN or Password"),
             LdapError::InsufficentAccessRights => write!(f, "Insufficent Access"),
             LdapError::EsyncRefreshRequired => write!(f, "An initial content sync is required. The current cookie should be considered invalid."),
@@ -173,6 +176,28 @@ impl LdapWriteTransport {
 
 impl LdapReadTransport {
     async fn next(&mut self) -> LdapResult<LdapMsg> {
+        loop {
+            let msg = self.next_msg().await?;
+            if !msg.is_unsolicited() {
+                return Ok(msg);
+            }
+            // Unsolicited notifications don't belong to any request, so
+            // handle them here rather than giving them to the caller.
+            match &msg.op {
+                LdapOp::ExtendedResponse(LdapExtendedResponse {
+                    name: Some(name), ..
+                }) if name == "1.3.6.1.4.1.1466.20036" => {
+                    info!(?msg, "server sent notice of disconnection");
+                    return Err(LdapError::TransportReadError);
+                }
+                _ => {
+                    warn!(?msg, "ignoring unsolicited notification");
+                }
+            }
+        }
+    }
+
+    async fn next_msg(&mut self) -> LdapResult<LdapMsg> {
         match self {
             LdapReadTransport::Plain(f) => f.next().await.transpose().map_err(|e| {
                 info!(?e, "transport error");
@@ -406,14 +431,14 @@ impl<'a> LdapClientBuilder<'a> {
             info!("tls configured");
             let (r, w) = tokio::io::split(tlsstream);
             (
-                LdapWriteTransport::Tls(FramedWrite::new(w, LdapCodec)),
-                LdapReadTransport::Tls(FramedRead::new(r, LdapCodec)),
+                LdapWriteTransport::Tls(FramedWrite::new(w, LdapCodec::default())),
+                LdapReadTransport::Tls(FramedRead::new(r, LdapCodec::default().role(CodecRole::Client))),
             )
         } else {
             let (r, w) = tokio::io::split(tcpstream);
             (
-                LdapWriteTransport::Plain(FramedWrite::new(w, LdapCodec)),
-                LdapReadTransport::Plain(FramedRead::new(r, LdapCodec)),
+                LdapWriteTransport::Plain(FramedWrite::new(w, LdapCodec::default())),
+                LdapReadTransport::Plain(FramedRead::new(r, LdapCodec::default().role(CodecRole::Client))),
             )
         };
 
@@ -450,6 +475,7 @@ impl LdapClient {
         let msg = LdapMsg {
             msgid,
             op: LdapOp::BindRequest(LdapBindRequest {
+                version: 3,
                 dn,
                 cred: LdapBindCred::Simple(pw),
             }),
@@ -54,25 +54,7 @@ impl LdapClient {
     ) -> crate::LdapResult<LdapSyncRepl> {
         let msgid = self.get_next_msgid();
 
-        let msg = LdapMsg {
-            msgid,
-            op: LdapOp::SearchRequest(LdapSearchRequest {
-                base: basedn,
-                scope: LdapSearchScope::Subtree,
-                aliases: LdapDerefAliases::Never,
-                sizelimit: 0,
-                timelimit: 0,
-                typesonly: false,
-                filter,
-                attrs: vec![],
-            }),
-            ctrl: vec![LdapControl::SyncRequest {
-                criticality: true,
-                mode,
-                cookie,
-                reload_hint: false,
-            }],
-        };
+        let msg = LdapMsg::sync_search(msgid, basedn, filter, mode, cookie);
 
         self.write_transport.send(msg).await?;
 
@@ -0,0 +1,146 @@
+// Decode a synthetic directory dump, as well as binds and search results,
+// and report how long it took and how many allocations were made. Run with
+// cargo bench -p ldap3_proto --bench decode
+//
+// Before ldap_decode took ownership of the parsed tag rather than cloning it:
+// bind:          10000 in 3.9ms, 12 allocations per message
+// search entry:  10000 in 12.4ms, 37 allocations per message
+// search done:   10000 in 4.1ms, 8 allocations per message
+//
+// After:
+// bind:          10000 in 2.9ms, 6 allocations per message
+// search entry:  10000 in 8.3ms, 18 allocations per message
+// search done:   10000 in 3.4ms, 4 allocations per message
+//
+// Sizing the values of each attribute with Vec::with_capacity, rather than
+// collecting them into the allocation of the parsed set:
+// search entry:  215800 allocations, against 185700 when collected
+
+use bytes::BytesMut;
+use ldap3_proto::proto::*;
+use ldap3_proto::LdapCodec;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use tokio_util::codec::{Decoder, Encoder};
+
+struct CountingAlloc;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const ENTRIES: usize = 10_000;
+
+fn entry(i: usize) -> LdapMsg {
+    // Most attributes have one or two values, with the odd group having
+    // many members, as in a typical directory.
+    let mut attributes = vec![
+        LdapPartialAttribute {
+            atype: "objectClass".to_string(),
+            vals: vec![b"top".to_vec(), b"person".to_vec()],
+        },
+        LdapPartialAttribute {
+            atype: "cn".to_string(),
+            vals: vec![format!("user{}", i).into_bytes()],
+        },
+        LdapPartialAttribute {
+            atype: "mail".to_string(),
+            vals: vec![format!("user{}@example.com", i).into_bytes()],
+        },
+    ];
+
+    if i % 100 == 0 {
+        attributes.push(LdapPartialAttribute {
+            atype: "member".to_string(),
+            vals: (0..50)
+                .map(|m| format!("uid=user{},dc=example,dc=com", m).into_bytes())
+                .collect(),
+        });
+    }
+
+    LdapMsg::new(
+        i as i32,
+        LdapOp::SearchResultEntry(LdapSearchResultEntry {
+            dn: format!("uid=user{},dc=example,dc=com", i),
+            attributes,
+        }),
+    )
+}
+
+fn bind(i: usize) -> LdapMsg {
+    LdapMsg::new(
+        i as i32,
+        LdapOp::BindRequest(LdapBindRequest::simple(
+            &format!("uid=user{},dc=example,dc=com", i),
+            "password",
+        )),
+    )
+}
+
+fn done(i: usize) -> LdapMsg {
+    LdapMsg::new(
+        i as i32,
+        LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::Success,
+            matcheddn: "".to_string(),
+            message: "".to_string(),
+            referral: vec![],
+        }),
+    )
+}
+
+fn bench(name: &str, gen: fn(usize) -> LdapMsg) {
+    let mut codec = LdapCodec::default();
+    let mut buf = BytesMut::new();
+    for i in 0..ENTRIES {
+        codec.encode(gen(i), &mut buf).expect("failed to encode");
+    }
+    let len = buf.len();
+
+    let start_allocs = ALLOCS.load(Ordering::Relaxed);
+    let start = Instant::now();
+
+    let mut count = 0;
+    while let Some(msg) = codec.decode(&mut buf).expect("failed to decode") {
+        count += 1;
+        drop(msg);
+    }
+
+    let elapsed = start.elapsed();
+    let allocs = ALLOCS.load(Ordering::Relaxed) - start_allocs;
+
+    assert_eq!(count, ENTRIES);
+    println!(
+        "{}: decoded {} messages ({} bytes) in {:?}, {} allocations ({} per message)",
+        name,
+        count,
+        len,
+        elapsed,
+        allocs,
+        allocs / count
+    );
+}
+
+fn main() {
+    bench("bind", bind);
+    bench("search entry", entry);
+    bench("search done", done);
+}
@@ -41,8 +41,8 @@ async fn main() -> Result<(), ()> {
             eprintln!("Failed to initialise TLS -> {:?}", e);
         })?;
 
-    let mut framed = Framed::new(tlsstream, LdapCodec);
-    // let mut framed = Framed::new(tcpstream, LdapCodec);
+    let mut framed = Framed::new(tlsstream, LdapCodec::default());
+    // let mut framed = Framed::new(tcpstream, LdapCodec::default());
 
     let dn = "uid=demo_user,ou=people,dc=example,dc=com".to_string();
     let pw = "password".to_string();
@@ -50,6 +50,7 @@ async fn main() -> Result<(), ()> {
     let msg = LdapMsg {
         msgid: 1,
         op: LdapOp::BindRequest(LdapBindRequest {
+            version: 3,
             dn,
             cred: LdapBindCred::Simple(pw),
         }),
@@ -11,7 +11,7 @@ use std::str::FromStr;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 use ldap3_proto::simple::*;
-use ldap3_proto::LdapCodec;
+use ldap3_proto::{CodecRole, LdapCodec};
 
 pub struct LdapSession {
     dn: String,
@@ -70,8 +70,8 @@ impl LdapSession {
 async fn handle_client(socket: TcpStream, _paddr: net::SocketAddr) {
     // Configure the codec etc.
     let (r, w) = tokio::io::split(socket);
-    let mut reqs = FramedRead::new(r, LdapCodec);
-    let mut resp = FramedWrite::new(w, LdapCodec);
+    let mut reqs = FramedRead::new(r, LdapCodec::default().role(CodecRole::Server));
+    let mut resp = FramedWrite::new(w, LdapCodec::default());
 
     let mut session = LdapSession {
         dn: "Anonymous".to_string(),
@@ -0,0 +1,34 @@
+//! The commonly used types, for glob import.
+//!
+//! ```
+//! use ldap3_proto::prelude::*;
+//!
+//! let msg = LdapMsg::new(
+//!     1,
+//!     LdapOp::SearchRequest(LdapSearchRequest {
+//!         base: "dc=example,dc=com".to_string(),
+//!         scope: LdapSearchScope::Subtree,
+//!         aliases: LdapDerefAliases::Never,
+//!         sizelimit: 0,
+//!         timelimit: 0,
+//!         typesonly: false,
+//!         filter: LdapFilter::Equality("uid".to_string(), "demo".to_string())
+//!             .and(parse_ldap_filter_str("(objectClass=*)").unwrap()),
+//!         attrs: vec!["cn".to_string()],
+//!     }),
+//! );
+//! assert_eq!(msg.op.kind(), LdapOpKind::SearchRequest);
+//! ```
+
+pub use crate::filter::parse_ldap_filter_str;
+pub use crate::proto::{
+    AttributeDescription, LdapBindCred, LdapBindRequest, LdapBindResponse, LdapControl,
+    LdapDerefAliases, LdapError, LdapFilter, LdapModify, LdapModifyRequest, LdapModifyType,
+    LdapMsg, LdapOp, LdapOpKind, LdapPartialAttribute, LdapProtoError, LdapResult, LdapResultCode,
+    LdapSearchRequest, LdapSearchResultEntry, LdapSearchResultEntryBuilder, LdapSearchScope,
+    RequestedAttributes,
+};
+pub use crate::{
+    encode_to, ldap_decode, ldap_decode_with, CodecRole, DecodeNeed, DecodeOptions, LdapCodec,
+    LdapMsgSummary,
+};
@@ -10,11 +10,14 @@ use lber::write as lber_write;
 use lber::parse::Parser;
 use lber::{Consumer, ConsumerState, Input};
 
+use crate::DecodeOptions;
 use bytes::BytesMut;
 use uuid::Uuid;
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::{From, TryFrom};
 use std::iter::{once, once_with};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct LdapMsg {
@@ -23,6 +26,38 @@ pub struct LdapMsg {
     pub ctrl: Vec<LdapControl>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LdapProtoError {
+    // The control was not a valid BER sequence.
+    ControlBerInvalid,
+    // The control requires a value, but none was present.
+    ControlValueMissing,
+    // The control value did not contain the expected fields.
+    ControlValueInvalid,
+    // The control oid is not known to us.
+    ControlUnsupported,
+    // The control is marked critical, but is not known to us.
+    ControlCriticalUnsupported,
+    // The same control was present more than once.
+    ControlDuplicate,
+    // The message id is out of range for this operation.
+    MessageIdInvalid,
+    // The input was not valid BER.
+    BerInvalid,
+    // The input was valid BER, but not a valid ldap message.
+    MessageInvalid,
+    // A modify request must have at least one change.
+    ModifyChangesEmpty,
+    // The control sequence had more elements than oid, criticality and value.
+    MalformedControl,
+    // An attribute description contained characters that are not allowed.
+    AttributeDescriptionInvalid,
+    // A message in a sync refresh was not valid for the current phase.
+    SyncStateInvalid,
+    // The message exceeded a limit in DecodeOptions.
+    DecodeLimitExceeded,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 #[repr(i64)]
 pub enum SyncRequestMode {
@@ -30,6 +65,15 @@ pub enum SyncRequestMode {
     RefreshAndPersist = 3,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+#[repr(i64)]
+pub enum LdapEntryChangeType {
+    Add = 1,
+    Delete = 2,
+    Modify = 4,
+    ModDn = 8,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 #[repr(i64)]
 pub enum SyncStateValue {
@@ -58,11 +102,190 @@ pub enum LdapControl {
         refresh_deletes: bool,
     },
     AdDirsync {
-        flags: i64,
+        // A 4 byte INTEGER on the wire, so the flags with the high bit set,
+        // such as incremental values, are negative.
+        flags: i32,
         // Msdn and wireshark disagree on the name oof this type.
         max_bytes: i64,
         cookie: Option<Vec<u8>>,
     },
+    // https://www.rfc-editor.org/rfc/rfc2696
+    SimplePagedResults {
+        size: i32,
+        cookie: Vec<u8>,
+    },
+    // https://www.rfc-editor.org/rfc/rfc2891
+    SortRequest {
+        criticality: bool,
+        keys: Vec<LdapSortKey>,
+    },
+    // https://datatracker.ietf.org/doc/html/draft-ietf-ldapext-psearch-03#section-4
+    PersistentSearch {
+        // A mask of LdapEntryChangeType values to be notified of.
+        change_types: i32,
+        changes_only: bool,
+        // Request an EntryChangeNotification with each entry.
+        return_ecs: bool,
+    },
+    // Returned with each entry of a persistent search (change notification)
+    // to describe what changed.
+    // https://datatracker.ietf.org/doc/html/draft-ietf-ldapext-psearch-03#section-5
+    EntryChangeNotification {
+        change_type: LdapEntryChangeType,
+        // Only present for ModDn
+        previous_dn: Option<String>,
+        change_number: Option<i64>,
+    },
+    // https://www.rfc-editor.org/rfc/rfc3672#section-3
+    Subentries {
+        criticality: bool,
+        visibility: bool,
+    },
+    // https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-adts/57056773-932c-4e55-9491-e13f49ba580c
+    AdExtendedDn {
+        criticality: bool,
+        // 0 returns the guid and sid as hex, 1 as their string forms.
+        flag: i64,
+    },
+    // https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-adts/6a72a4a7-1d21-4bba-9fc1-30b3e5ce1bb6
+    // AD change notification. Sent with an asynchronous search, after which
+    // the server returns each entry in scope as it changes, until the search
+    // is abandoned. AD requires it to be critical, and it has no value. The
+    // entries carry no control, so a change is seen as the entry itself.
+    AdNotification {
+        criticality: bool,
+    },
+    // https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-adts/3888c2b7-35b9-45b7-afeb-b772aa932dd0
+    // Which parts of nTSecurityDescriptor to return. A mask of owner (1),
+    // group (2), dacl (4) and sacl (8).
+    AdSdFlags {
+        flags: i32,
+    },
+    // https://datatracker.ietf.org/doc/html/draft-ietf-ldapext-acl-model-08#section-9.1
+    GetEffectiveRights {
+        // As an authzid, such as dn:cn=demo,dc=example,dc=com
+        authzid: String,
+        attributes: Vec<String>,
+    },
+    // https://www.rfc-editor.org/rfc/rfc5805#section-2.2
+    // Marks an update as part of a transaction. Always critical.
+    TransactionSpecification {
+        identifier: Vec<u8>,
+    },
+    // https://datatracker.ietf.org/doc/html/draft-zeilenga-ldap-relax-03
+    // Allows changes to attributes that are normally read only, such as
+    // entryUUID, during a migration. Has no value.
+    RelaxRules {
+        criticality: bool,
+    },
+    // https://datatracker.ietf.org/doc/html/draft-armijo-ldap-treedelete-02
+    // Deletes the entry and all of its subordinates. Has no value.
+    TreeDelete {
+        criticality: bool,
+    },
+    // https://www.rfc-editor.org/rfc/rfc4528
+    // The operation only proceeds if the entry matches the filter.
+    Assertion {
+        criticality: bool,
+        filter: LdapFilter,
+    },
+    // https://www.rfc-editor.org/rfc/rfc3876
+    // Only values matching one of the filters are returned. The filters may
+    // not be and, or or not.
+    MatchedValues {
+        criticality: bool,
+        filters: Vec<LdapFilter>,
+    },
+    // https://datatracker.ietf.org/doc/html/draft-vchu-ldap-pwd-policy-00
+    // The account usability control of Sun/OpenDJ/389ds. AD has no
+    // equivalent. The request has no value, and the server returns the state
+    // of the account with each entry.
+    AccountUsableRequest {
+        criticality: bool,
+    },
+    AccountUsableResponse(LdapAccountUsability),
+    // https://datatracker.ietf.org/doc/html/draft-behera-ldap-password-policy-11#section-6.1
+    // The request has no value. The response may be attached to any
+    // response, but is most often seen on a bind.
+    PasswordPolicyRequest {
+        criticality: bool,
+    },
+    PasswordPolicyResponse {
+        warning: Option<LdapPasswordPolicyWarning>,
+        error: Option<LdapPasswordPolicyError>,
+    },
+    // A control we could not interpret, either because the oid is unknown
+    // or the value was invalid. The value is the content of the octet
+    // string, as it was on the wire.
+    Raw {
+        oid: String,
+        criticality: bool,
+        value: Option<Vec<u8>>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapPasswordPolicyWarning {
+    TimeBeforeExpiration(i64),
+    GraceAuthNsRemaining(i64),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[repr(i64)]
+pub enum LdapPasswordPolicyError {
+    PasswordExpired = 0,
+    AccountLocked = 1,
+    ChangeAfterReset = 2,
+    PasswordModNotAllowed = 3,
+    MustSupplyOldPassword = 4,
+    InsufficientPasswordQuality = 5,
+    PasswordTooShort = 6,
+    PasswordTooYoung = 7,
+    PasswordInHistory = 8,
+    PasswordTooLong = 9,
+}
+
+impl TryFrom<i64> for LdapPasswordPolicyError {
+    type Error = ();
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(LdapPasswordPolicyError::PasswordExpired),
+            1 => Ok(LdapPasswordPolicyError::AccountLocked),
+            2 => Ok(LdapPasswordPolicyError::ChangeAfterReset),
+            3 => Ok(LdapPasswordPolicyError::PasswordModNotAllowed),
+            4 => Ok(LdapPasswordPolicyError::MustSupplyOldPassword),
+            5 => Ok(LdapPasswordPolicyError::InsufficientPasswordQuality),
+            6 => Ok(LdapPasswordPolicyError::PasswordTooShort),
+            7 => Ok(LdapPasswordPolicyError::PasswordTooYoung),
+            8 => Ok(LdapPasswordPolicyError::PasswordInHistory),
+            9 => Ok(LdapPasswordPolicyError::PasswordTooLong),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapAccountUsability {
+    // The account may bind. The time until the password expires.
+    Available {
+        seconds_before_expiration: i64,
+    },
+    NotAvailable {
+        inactive: bool,
+        // The password was reset, and must be changed.
+        reset: bool,
+        expired: bool,
+        remaining_grace: Option<i64>,
+        seconds_before_unlock: Option<i64>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapSortKey {
+    pub attribute_type: String,
+    pub ordering_rule: Option<String>,
+    pub reverse_order: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -144,6 +367,12 @@ pub enum LdapOp {
     // https://tools.ietf.org/html/rfc4511#section-4.8
     DelRequest(String),
     DelResponse(LdapResult),
+    // https://tools.ietf.org/html/rfc4511#section-4.9
+    ModifyDNRequest(LdapModifyDNRequest),
+    ModifyDNResponse(LdapResult),
+    // https://tools.ietf.org/html/rfc4511#section-4.10
+    CompareRequest(LdapCompareRequest),
+    CompareResponse(LdapResult),
     // https://tools.ietf.org/html/rfc4511#section-4.11
     AbandonRequest(i32),
     // https://tools.ietf.org/html/rfc4511#section-4.12
@@ -153,21 +382,142 @@ pub enum LdapOp {
     IntermediateResponse(LdapIntermediateResponse),
 }
 
+// The kind of an LdapOp, without any of its content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LdapOpKind {
+    BindRequest,
+    BindResponse,
+    UnbindRequest,
+    SearchRequest,
+    SearchResultEntry,
+    SearchResultDone,
+    ModifyRequest,
+    ModifyResponse,
+    AddRequest,
+    AddResponse,
+    DelRequest,
+    DelResponse,
+    ModifyDNRequest,
+    ModifyDNResponse,
+    CompareRequest,
+    CompareResponse,
+    AbandonRequest,
+    ExtendedRequest,
+    ExtendedResponse,
+    IntermediateResponse,
+}
+
+impl LdapOpKind {
+    // The BER application tag id of this op.
+    // https://tools.ietf.org/html/rfc4511#section-4.2
+    pub fn application_id(&self) -> u64 {
+        match self {
+            LdapOpKind::BindRequest => 0,
+            LdapOpKind::BindResponse => 1,
+            LdapOpKind::UnbindRequest => 2,
+            LdapOpKind::SearchRequest => 3,
+            LdapOpKind::SearchResultEntry => 4,
+            LdapOpKind::SearchResultDone => 5,
+            LdapOpKind::ModifyRequest => 6,
+            LdapOpKind::ModifyResponse => 7,
+            LdapOpKind::AddRequest => 8,
+            LdapOpKind::AddResponse => 9,
+            LdapOpKind::DelRequest => 10,
+            LdapOpKind::DelResponse => 11,
+            LdapOpKind::ModifyDNRequest => 12,
+            LdapOpKind::ModifyDNResponse => 13,
+            LdapOpKind::CompareRequest => 14,
+            LdapOpKind::CompareResponse => 15,
+            LdapOpKind::AbandonRequest => 16,
+            LdapOpKind::ExtendedRequest => 23,
+            LdapOpKind::ExtendedResponse => 24,
+            LdapOpKind::IntermediateResponse => 25,
+        }
+    }
+}
+
+impl TryFrom<u64> for LdapOpKind {
+    type Error = ();
+
+    // From the BER application tag id of an op.
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(LdapOpKind::BindRequest),
+            1 => Ok(LdapOpKind::BindResponse),
+            2 => Ok(LdapOpKind::UnbindRequest),
+            3 => Ok(LdapOpKind::SearchRequest),
+            4 => Ok(LdapOpKind::SearchResultEntry),
+            5 => Ok(LdapOpKind::SearchResultDone),
+            6 => Ok(LdapOpKind::ModifyRequest),
+            7 => Ok(LdapOpKind::ModifyResponse),
+            8 => Ok(LdapOpKind::AddRequest),
+            9 => Ok(LdapOpKind::AddResponse),
+            10 => Ok(LdapOpKind::DelRequest),
+            11 => Ok(LdapOpKind::DelResponse),
+            12 => Ok(LdapOpKind::ModifyDNRequest),
+            13 => Ok(LdapOpKind::ModifyDNResponse),
+            14 => Ok(LdapOpKind::CompareRequest),
+            15 => Ok(LdapOpKind::CompareResponse),
+            16 => Ok(LdapOpKind::AbandonRequest),
+            23 => Ok(LdapOpKind::ExtendedRequest),
+            24 => Ok(LdapOpKind::ExtendedResponse),
+            25 => Ok(LdapOpKind::IntermediateResponse),
+            _ => Err(()),
+        }
+    }
+}
+
 #[derive(Clone, PartialEq)]
 pub enum LdapBindCred {
-    Simple(String), // Sasl
+    Simple(String),
+    // The first step of a multi-step sasl exchange may have no credentials,
+    // which is distinct from empty credentials.
+    Sasl {
+        mechanism: String,
+        credentials: Option<Vec<u8>>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct LdapBindRequest {
+    // Only version 3 is supported, but we decode any valid version so that
+    // servers can reject older clients gracefully.
+    pub version: i32,
     pub dn: String,
     pub cred: LdapBindCred,
 }
 
+impl LdapBindRequest {
+    pub fn anonymous() -> Self {
+        Self::simple("", "")
+    }
+
+    pub fn simple(dn: &str, password: &str) -> Self {
+        LdapBindRequest {
+            version: 3,
+            dn: dn.to_string(),
+            cred: LdapBindCred::Simple(password.to_string()),
+        }
+    }
+
+    // The dn is usually empty, as the identity comes from the mechanism.
+    pub fn sasl(dn: &str, mechanism: &str, credentials: Option<Vec<u8>>) -> Self {
+        LdapBindRequest {
+            version: 3,
+            dn: dn.to_string(),
+            cred: LdapBindCred::Sasl {
+                mechanism: mechanism.to_string(),
+                credentials,
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct LdapBindResponse {
     pub res: LdapResult,
-    pub saslcreds: Option<String>,
+    // serverSaslCreds, sent as [7].
+    pub saslcreds: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -201,10 +551,10 @@ pub enum LdapFilter {
     Not(Box<LdapFilter>),
     Equality(String, String),
     Substring(String, LdapSubstringFilter),
-    //GE
-    //LE
+    GreaterOrEqual(String, String),
+    LessOrEqual(String, String),
     Present(String),
-    //Approx
+    Approx(String, String),
     //Extensible
 }
 
@@ -231,6 +581,375 @@ pub struct LdapPartialAttribute {
 // Attribute requires at least one value.
 pub type LdapAttribute = LdapPartialAttribute;
 
+impl LdapPartialAttribute {
+    // An attribute with no values, as returned for a typesOnly search.
+    pub fn type_only(name: &str) -> Self {
+        LdapPartialAttribute {
+            atype: name.to_string(),
+            vals: Vec::new(),
+        }
+    }
+
+    // A chunk of a large attribute as AD returns it, named with a range
+    // option. An end of None marks the last chunk.
+    pub fn ranged(base: &str, start: u32, end: Option<u32>, vals: Vec<Vec<u8>>) -> Self {
+        let atype = match end {
+            Some(end) => format!("{};range={}-{}", base, start, end),
+            None => format!("{};range={}-*", base, start),
+        };
+        LdapPartialAttribute { atype, vals }
+    }
+
+    // Values are a SET, so by default we keep them in the order given. For
+    // reproducible output, this sorts them by their encoding as DER does.
+    pub fn canonicalize(&mut self) {
+        self.vals.sort_by_cached_key(|v| {
+            let mut bytes = BytesMut::new();
+            let tag = Tag::OctetString(OctetString {
+                inner: v.clone(),
+                ..Default::default()
+            });
+            // Writing to a BytesMut can't fail.
+            let _ = lber_write::encode_into(&mut bytes, tag.into_structure());
+            bytes
+        });
+    }
+
+    // Reject attribute names with characters that are not allowed, such as
+    // spaces or control characters. Without the strict feature this always
+    // returns Ok.
+    pub fn validate(&self) -> Result<(), LdapProtoError> {
+        if cfg!(feature = "strict") {
+            AttributeDescription::validate(&self.atype)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl LdapSearchResultEntry {
+    // See LdapPartialAttribute::canonicalize
+    pub fn canonicalize(&mut self) {
+        self.attributes
+            .iter_mut()
+            .for_each(LdapPartialAttribute::canonicalize);
+    }
+
+    // The minimal RootDSE a server must answer a base search of "" with.
+    // Attributes with no values are left out, as an entry may not hold an
+    // empty attribute. https://tools.ietf.org/html/rfc4512#section-5.1
+    pub fn root_dse(
+        naming_contexts: &[&str],
+        supported_controls: &[&str],
+        supported_extensions: &[&str],
+        supported_sasl: &[&str],
+        vendor_name: &str,
+        vendor_version: &str,
+    ) -> Self {
+        let attr = |atype: &str, vals: &[&str]| LdapPartialAttribute {
+            atype: atype.to_string(),
+            vals: vals.iter().map(|v| v.as_bytes().to_vec()).collect(),
+        };
+
+        let attributes = vec![
+            attr("objectClass", &["top"]),
+            attr("namingContexts", naming_contexts),
+            attr("supportedControl", supported_controls),
+            attr("supportedExtension", supported_extensions),
+            attr("supportedLDAPVersion", &["3"]),
+            attr("supportedSASLMechanisms", supported_sasl),
+            attr("vendorName", &[vendor_name]),
+            attr("vendorVersion", &[vendor_version]),
+        ]
+        .into_iter()
+        .filter(|a| !a.vals.is_empty())
+        .collect();
+
+        LdapSearchResultEntry {
+            dn: "".to_string(),
+            attributes,
+        }
+    }
+
+    // Add the operational attributes servers return for "+".
+    // https://tools.ietf.org/html/rfc4512#section-3.4
+    // https://tools.ietf.org/html/rfc4530
+    pub fn with_operational(
+        mut self,
+        creators_name: &str,
+        create_ts: SystemTime,
+        modifiers_name: &str,
+        modify_ts: SystemTime,
+        entry_uuid: Uuid,
+    ) -> Self {
+        let attr = |atype: &str, val: String| LdapPartialAttribute {
+            atype: atype.to_string(),
+            vals: vec![val.into_bytes()],
+        };
+
+        self.attributes.extend([
+            attr("creatorsName", creators_name.to_string()),
+            attr("createTimestamp", generalized_time(create_ts)),
+            attr("modifiersName", modifiers_name.to_string()),
+            attr("modifyTimestamp", generalized_time(modify_ts)),
+            attr("entryUUID", entry_uuid.hyphenated().to_string()),
+        ]);
+        self
+    }
+
+    // The changes that turn this entry into target. Attributes are matched
+    // by their full description ignoring case, and values are compared as
+    // unordered sets. An attribute only in target is added, one only in self
+    // is deleted, and one whose values differ is replaced.
+    pub fn diff_to_modify(&self, target: &LdapSearchResultEntry) -> LdapModifyRequest {
+        fn find<'a>(
+            entry: &'a LdapSearchResultEntry,
+            atype: &str,
+        ) -> Option<&'a LdapPartialAttribute> {
+            entry
+                .attributes
+                .iter()
+                .find(|a| a.atype.eq_ignore_ascii_case(atype))
+        }
+
+        fn val_set(a: &LdapPartialAttribute) -> BTreeSet<&[u8]> {
+            a.vals.iter().map(Vec::as_slice).collect()
+        }
+
+        let removed_or_changed =
+            self.attributes
+                .iter()
+                .filter_map(|a| match find(target, &a.atype) {
+                    None => Some(LdapModify::delete_attribute(&a.atype)),
+                    Some(t) if val_set(t) != val_set(a) => Some(LdapModify {
+                        operation: LdapModifyType::Replace,
+                        modification: LdapPartialAttribute {
+                            atype: a.atype.clone(),
+                            vals: t.vals.clone(),
+                        },
+                    }),
+                    Some(_) => None,
+                });
+
+        let added = target
+            .attributes
+            .iter()
+            .filter(|a| find(self, &a.atype).is_none())
+            .map(|a| LdapModify {
+                operation: LdapModifyType::Add,
+                modification: a.clone(),
+            });
+
+        LdapModifyRequest {
+            dn: self.dn.clone(),
+            changes: removed_or_changed.chain(added).collect(),
+        }
+    }
+}
+
+// Format a time as a GeneralizedTime in UTC, such as 20231114221320Z. Times
+// before the epoch are clamped to it.
+// https://tools.ietf.org/html/rfc4517#section-3.3.13
+pub fn generalized_time(ts: SystemTime) -> String {
+    let secs = ts
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Days to a civil date, from Howard Hinnant's date algorithms.
+    // http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+// Parse a GeneralizedTime value, such as 20240101123000Z, 202401011230+1030
+// or 20240101123000.25Z. A fraction applies to the last unit present, so
+// 2024010112.5Z is half past twelve.
+// https://tools.ietf.org/html/rfc4517#section-3.3.13
+#[cfg(feature = "time")]
+pub fn parse_generalized_time(value: &[u8]) -> Option<time::OffsetDateTime> {
+    use time::{Date, Duration, Month, PrimitiveDateTime, Time, UtcOffset};
+
+    fn split_digits(s: &str) -> (&str, &str) {
+        s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()))
+    }
+
+    let s = std::str::from_utf8(value).ok()?;
+    let num = |s: &str| s.parse::<u8>().ok();
+
+    let (digits, rest) = split_digits(s);
+    let (year, month, day, hour) = (
+        digits.get(0..4)?.parse::<i32>().ok()?,
+        num(digits.get(4..6)?)?,
+        num(digits.get(6..8)?)?,
+        num(digits.get(8..10)?)?,
+    );
+    let (minute, second, unit) = match digits.len() {
+        10 => (0, 0, 3600.0),
+        12 => (num(&digits[10..12])?, 0, 60.0),
+        14 => (num(&digits[10..12])?, num(&digits[12..14])?, 1.0),
+        _ => return None,
+    };
+
+    let (fraction, rest) = match rest.strip_prefix(|c| c == '.' || c == ',') {
+        Some(rest) => {
+            let (f, rest) = split_digits(rest);
+            (
+                format!("0.{}", f)
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|_| !f.is_empty())?,
+                rest,
+            )
+        }
+        None => (0.0, rest),
+    };
+
+    let offset = match rest.as_bytes() {
+        b"Z" => UtcOffset::UTC,
+        // Only digits may follow the sign, which also keeps the slices below
+        // on char boundaries.
+        [sign @ (b'+' | b'-'), digits @ ..]
+            if (digits.len() == 2 || digits.len() == 4)
+                && digits.iter().all(u8::is_ascii_digit) =>
+        {
+            let h = rest[1..3].parse::<i8>().ok()?;
+            let m = rest.get(3..5).map_or(Some(0), |m| m.parse::<i8>().ok())?;
+            let sign = if *sign == b'-' { -1 } else { 1 };
+            UtcOffset::from_hms(sign * h, sign * m, 0).ok()?
+        }
+        _ => return None,
+    };
+
+    // A leap second is carried into the next minute.
+    let (second, leap) = if second == 60 { (59, 1) } else { (second, 0) };
+    let date = Date::from_calendar_date(year, Month::try_from(month).ok()?, day).ok()?;
+    let time = Time::from_hms(hour, minute, second).ok()?;
+    Some(
+        PrimitiveDateTime::new(date, time).assume_offset(offset)
+            + Duration::seconds(leap)
+            + Duration::seconds_f64(fraction * unit),
+    )
+}
+
+#[cfg(feature = "time")]
+impl LdapSearchResultEntry {
+    // The first value of the attribute, parsed as a GeneralizedTime.
+    pub fn get_time(&self, atype: &str) -> Option<time::OffsetDateTime> {
+        self.attributes
+            .iter()
+            .find(|a| a.atype.eq_ignore_ascii_case(atype))
+            .and_then(|a| a.vals.first())
+            .and_then(|v| parse_generalized_time(v))
+    }
+
+    pub fn create_timestamp(&self) -> Option<time::OffsetDateTime> {
+        self.get_time("createTimestamp")
+    }
+
+    pub fn modify_timestamp(&self) -> Option<time::OffsetDateTime> {
+        self.get_time("modifyTimestamp")
+    }
+}
+
+// An attribute type with its options, such as cn;lang-en or
+// userCertificate;binary. https://tools.ietf.org/html/rfc4512#section-2.5
+#[derive(Debug, Clone, PartialEq)]
+pub struct AttributeDescription {
+    pub base: String,
+    pub options: Vec<String>,
+}
+
+impl AttributeDescription {
+    // attributedescription = attributetype options
+    // where the type is a descr or numericoid, and options are keychars. The
+    // AD range option is also accepted, even though it has an '='.
+    pub fn validate(atype: &str) -> Result<(), LdapProtoError> {
+        let keychars =
+            |s: &str| !s.is_empty() && s.bytes().all(|c| c.is_ascii_alphanumeric() || c == b'-');
+
+        let mut iter = atype.split(';');
+        let base = iter.next().unwrap_or_default();
+
+        let descr = keychars(base) && base.starts_with(|c: char| c.is_ascii_alphabetic());
+        let numericoid = base.contains('.')
+            && base
+                .split('.')
+                .all(|n| !n.is_empty() && n.bytes().all(|c| c.is_ascii_digit()));
+
+        if (descr || numericoid) && iter.all(|o| keychars(o) || parse_range_option(o).is_some()) {
+            Ok(())
+        } else {
+            Err(LdapProtoError::AttributeDescriptionInvalid)
+        }
+    }
+
+    pub fn parse(atype: &str) -> Self {
+        let mut iter = atype.split(';');
+        // split always yields at least one item.
+        let base = iter.next().unwrap_or_default().to_string();
+        let options = iter.map(str::to_string).collect();
+        AttributeDescription { base, options }
+    }
+
+    // Attribute types and options are case insensitive.
+    pub fn is_type(&self, base: &str) -> bool {
+        self.base.eq_ignore_ascii_case(base)
+    }
+
+    pub fn has_option(&self, option: &str) -> bool {
+        self.options.iter().any(|o| o.eq_ignore_ascii_case(option))
+    }
+
+    // The AD range option, such as member;range=0-1499. The end is None for
+    // the last chunk, which AD sends as range=1500-*.
+    pub fn range(&self) -> Option<(u32, Option<u32>)> {
+        self.options.iter().find_map(|o| parse_range_option(o))
+    }
+}
+
+fn parse_range_option(option: &str) -> Option<(u32, Option<u32>)> {
+    let (name, range) = option.split_once('=')?;
+    if !name.eq_ignore_ascii_case("range") {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let start = start.parse().ok()?;
+    let end = match end {
+        "*" => None,
+        e => Some(e.parse().ok()?),
+    };
+    Some((start, end))
+}
+
+impl std::fmt::Display for AttributeDescription {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.base)?;
+        for o in &self.options {
+            write!(f, ";{}", o)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct LdapSearchResultEntry {
     pub dn: String,
@@ -243,6 +962,23 @@ pub struct LdapAddRequest {
     pub attributes: Vec<LdapAttribute>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapModifyDNRequest {
+    pub dn: String,
+    pub newrdn: String,
+    pub deleteoldrdn: bool,
+    // Move the entry under this dn, rather than keeping its parent.
+    pub new_superior: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapCompareRequest {
+    pub dn: String,
+    pub atype: String,
+    // The value may be binary, such as an objectGUID or objectSid.
+    pub assertion_value: Vec<u8>,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct LdapModifyRequest {
     pub dn: String,
@@ -255,6 +991,27 @@ pub struct LdapModify {
     pub modification: LdapPartialAttribute,
 }
 
+impl LdapModify {
+    // A Replace with no values, which removes the attribute if present, and
+    // succeeds if it is not.
+    pub fn remove(name: &str) -> Self {
+        LdapModify {
+            operation: LdapModifyType::Replace,
+            modification: LdapPartialAttribute::type_only(name),
+        }
+    }
+
+    // A Delete with no values, which removes the attribute, but fails with
+    // NoSuchAttribute if it is not present. Some servers also treat the two
+    // differently for access control and replication.
+    pub fn delete_attribute(name: &str) -> Self {
+        LdapModify {
+            operation: LdapModifyType::Delete,
+            modification: LdapPartialAttribute::type_only(name),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 #[repr(i64)]
 pub enum LdapModifyType {
@@ -271,7 +1028,7 @@ pub struct LdapExtendedRequest {
     pub value: Option<Vec<u8>>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct LdapExtendedResponse {
     pub res: LdapResult,
     // 10
@@ -321,11 +1078,53 @@ pub struct LdapWhoamiResponse {
     pub dn: Option<String>,
 }
 
+// https://www.rfc-editor.org/rfc/rfc4513#section-5.2.1.8
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapAuthzId {
+    Anonymous,
+    Dn(String),
+    // AD returns u:DOMAIN\user
+    User {
+        domain: Option<String>,
+        user: String,
+    },
+}
+
+impl LdapWhoamiResponse {
+    // Parse the authzid. None if it is not in a form we know.
+    pub fn authzid(&self) -> Option<LdapAuthzId> {
+        let authzid = match self.dn.as_deref() {
+            None | Some("") => return Some(LdapAuthzId::Anonymous),
+            Some(a) => a,
+        };
+
+        if let Some(dn) = authzid.strip_prefix("dn:") {
+            return Some(LdapAuthzId::Dn(dn.to_string()));
+        }
+
+        authzid
+            .strip_prefix("u:")
+            .map(|u| match u.split_once('\\') {
+                Some((domain, user)) => LdapAuthzId::User {
+                    domain: Some(domain.to_string()),
+                    user: user.to_string(),
+                },
+                None => LdapAuthzId::User {
+                    domain: None,
+                    user: u.to_string(),
+                },
+            })
+    }
+}
+
 impl TryFrom<&LdapExtendedResponse> for LdapWhoamiResponse {
     type Error = ();
     fn try_from(value: &LdapExtendedResponse) -> Result<Self, Self::Error> {
-        if value.name.is_some() {
-            return Err(());
+        // Some servers echo the request oid, even though rfc4532 says it
+        // should be absent.
+        match value.name.as_deref() {
+            None | Some("1.3.6.1.4.1.4203.1.11.3") => {}
+            Some(_) => return Err(()),
         }
 
         let dn = value
@@ -337,51 +1136,192 @@ impl TryFrom<&LdapExtendedResponse> for LdapWhoamiResponse {
     }
 }
 
-#[derive(Clone, PartialEq)]
-pub struct LdapPasswordModifyRequest {
-    pub user_identity: Option<String>,
-    pub old_password: Option<String>,
-    pub new_password: Option<String>,
-}
+// https://www.rfc-editor.org/rfc/rfc5805#section-2.1
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapStartTransactionRequest {}
 
 #[derive(Debug, Clone, PartialEq)]
-pub struct LdapPasswordModifyResponse {
+pub struct LdapStartTransactionResponse {
     pub res: LdapResult,
-    pub gen_password: Option<String>,
+    // Only present on success.
+    pub identifier: Option<Vec<u8>>,
 }
 
-impl From<LdapPasswordModifyRequest> for LdapExtendedRequest {
-    fn from(value: LdapPasswordModifyRequest) -> LdapExtendedRequest {
-        let inner: Vec<_> = vec![
-            value.user_identity.map(|s| {
-                Tag::OctetString(OctetString {
-                    class: TagClass::Context,
-                    id: 0,
-                    inner: Vec::from(s),
-                })
-            }),
-            value.old_password.map(|s| {
-                Tag::OctetString(OctetString {
-                    class: TagClass::Context,
-                    id: 1,
-                    inner: Vec::from(s),
-                })
-            }),
-            value.new_password.map(|s| {
-                Tag::OctetString(OctetString {
-                    class: TagClass::Context,
-                    id: 2,
-                    inner: Vec::from(s),
-                })
-            }),
-        ];
-
-        let tag = Tag::Sequence(Sequence {
-            inner: inner.into_iter().flatten().collect(),
-            ..Default::default()
-        });
-
-        let mut bytes = BytesMut::new();
+// https://www.rfc-editor.org/rfc/rfc5805#section-2.3
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapEndTransactionRequest {
+    // false to abort the transaction.
+    pub commit: bool,
+    pub identifier: Vec<u8>,
+}
+
+impl From<LdapStartTransactionRequest> for LdapExtendedRequest {
+    fn from(_value: LdapStartTransactionRequest) -> LdapExtendedRequest {
+        LdapExtendedRequest {
+            name: "1.3.6.1.1.21.1".to_string(),
+            value: None,
+        }
+    }
+}
+
+impl TryFrom<&LdapExtendedRequest> for LdapStartTransactionRequest {
+    type Error = ();
+    fn try_from(value: &LdapExtendedRequest) -> Result<Self, Self::Error> {
+        if value.name != "1.3.6.1.1.21.1" || value.value.is_some() {
+            return Err(());
+        }
+        Ok(LdapStartTransactionRequest {})
+    }
+}
+
+impl From<LdapStartTransactionResponse> for LdapExtendedResponse {
+    fn from(value: LdapStartTransactionResponse) -> LdapExtendedResponse {
+        LdapExtendedResponse {
+            res: value.res,
+            // responseName is absent, and the value is the identifier as is.
+            name: None,
+            value: value.identifier,
+        }
+    }
+}
+
+impl TryFrom<&LdapExtendedResponse> for LdapStartTransactionResponse {
+    type Error = ();
+    fn try_from(value: &LdapExtendedResponse) -> Result<Self, Self::Error> {
+        match value.name.as_deref() {
+            None | Some("1.3.6.1.1.21.1") => {}
+            Some(_) => return Err(()),
+        }
+
+        Ok(LdapStartTransactionResponse {
+            res: value.res.clone(),
+            identifier: value.value.clone(),
+        })
+    }
+}
+
+impl From<LdapEndTransactionRequest> for LdapExtendedRequest {
+    fn from(value: LdapEndTransactionRequest) -> LdapExtendedRequest {
+        // commit is DEFAULT TRUE, so it is only sent to abort.
+        let inner: Vec<_> = vec![
+            (!value.commit).then(|| {
+                Tag::Boolean(Boolean {
+                    inner: false,
+                    ..Default::default()
+                })
+            }),
+            Some(Tag::OctetString(OctetString {
+                inner: value.identifier,
+                ..Default::default()
+            })),
+        ];
+
+        let tag = Tag::Sequence(Sequence {
+            inner: inner.into_iter().flatten().collect(),
+            ..Default::default()
+        });
+
+        let mut bytes = BytesMut::new();
+
+        lber_write::encode_into(&mut bytes, tag.into_structure())
+            .expect("writing to a BytesMut can't fail");
+
+        LdapExtendedRequest {
+            name: "1.3.6.1.1.21.3".to_string(),
+            value: Some(bytes.to_vec()),
+        }
+    }
+}
+
+impl TryFrom<&LdapExtendedRequest> for LdapEndTransactionRequest {
+    type Error = ();
+    fn try_from(value: &LdapExtendedRequest) -> Result<Self, Self::Error> {
+        if value.name != "1.3.6.1.1.21.3" {
+            return Err(());
+        }
+
+        let buf = value.value.as_ref().ok_or(())?;
+
+        let mut parser = Parser::new();
+        let msg = match *parser.handle(Input::Element(buf)) {
+            ConsumerState::Done(_size, ref msg) => msg,
+            _ => return Err(()),
+        };
+
+        let mut seq = msg
+            .clone()
+            .match_id(Types::Sequence as u64)
+            .and_then(|t| t.expect_constructed())
+            .ok_or(())?;
+
+        let identifier = seq
+            .pop()
+            .and_then(|t| t.match_id(Types::OctetString as u64))
+            .and_then(|t| t.expect_primitive())
+            .ok_or(())?;
+
+        let commit = match seq.pop() {
+            Some(t) => t
+                .match_id(Types::Boolean as u64)
+                .and_then(|t| t.expect_primitive())
+                .and_then(ber_bool_to_bool)
+                .ok_or(())?,
+            None => true,
+        };
+
+        if !seq.is_empty() {
+            return Err(());
+        }
+
+        Ok(LdapEndTransactionRequest { commit, identifier })
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct LdapPasswordModifyRequest {
+    pub user_identity: Option<String>,
+    pub old_password: Option<String>,
+    pub new_password: Option<String>,
+}
+
+#[derive(Clone, PartialEq)]
+pub struct LdapPasswordModifyResponse {
+    pub res: LdapResult,
+    pub gen_password: Option<String>,
+}
+
+impl From<LdapPasswordModifyRequest> for LdapExtendedRequest {
+    fn from(value: LdapPasswordModifyRequest) -> LdapExtendedRequest {
+        let inner: Vec<_> = vec![
+            value.user_identity.map(|s| {
+                Tag::OctetString(OctetString {
+                    class: TagClass::Context,
+                    id: 0,
+                    inner: Vec::from(s),
+                })
+            }),
+            value.old_password.map(|s| {
+                Tag::OctetString(OctetString {
+                    class: TagClass::Context,
+                    id: 1,
+                    inner: Vec::from(s),
+                })
+            }),
+            value.new_password.map(|s| {
+                Tag::OctetString(OctetString {
+                    class: TagClass::Context,
+                    id: 2,
+                    inner: Vec::from(s),
+                })
+            }),
+        ];
+
+        let tag = Tag::Sequence(Sequence {
+            inner: inner.into_iter().flatten().collect(),
+            ..Default::default()
+        });
+
+        let mut bytes = BytesMut::new();
 
         lber_write::encode_into(&mut bytes, tag.into_structure()).unwrap();
 
@@ -517,6 +1457,29 @@ impl From<LdapBindCred> for Tag {
                 class: TagClass::Context,
                 inner: Vec::from(pw),
             }),
+            LdapBindCred::Sasl {
+                mechanism,
+                credentials,
+            } => {
+                let inner: Vec<_> = vec![
+                    Some(Tag::OctetString(OctetString {
+                        inner: Vec::from(mechanism),
+                        ..Default::default()
+                    })),
+                    credentials.map(|c| {
+                        Tag::OctetString(OctetString {
+                            inner: c,
+                            ..Default::default()
+                        })
+                    }),
+                ];
+
+                Tag::Sequence(Sequence {
+                    id: 3,
+                    class: TagClass::Context,
+                    inner: inner.into_iter().flatten().collect(),
+                })
+            }
         }
     }
 }
@@ -534,6 +1497,242 @@ impl LdapMsg {
         LdapMsg { msgid, op, ctrl }
     }
 
+    // Delete dn and everything below it. The control is critical, so a
+    // server without tree delete refuses rather than failing with
+    // notAllowedOnNonLeaf part way.
+    pub fn delete_tree(msgid: i32, dn: &str) -> Self {
+        LdapMsg::new_with_ctrls(
+            msgid,
+            LdapOp::DelRequest(dn.to_string()),
+            vec![LdapControl::TreeDelete { criticality: true }],
+        )
+    }
+
+    // Rename dn to new_rdn, and if new_superior is given, move it there.
+    pub fn rename(
+        msgid: i32,
+        dn: &str,
+        new_rdn: &str,
+        delete_old: bool,
+        new_superior: Option<&str>,
+    ) -> Self {
+        LdapMsg::new(
+            msgid,
+            LdapOp::ModifyDNRequest(LdapModifyDNRequest {
+                dn: dn.to_string(),
+                newrdn: new_rdn.to_string(),
+                deleteoldrdn: delete_old,
+                new_superior: new_superior.map(str::to_string),
+            }),
+        )
+    }
+
+    // A subtree search with a content sync (rfc4533) request attached. The
+    // control is marked critical, as a server that ignores it would return
+    // a plain search instead of a sync.
+    pub fn sync_search(
+        msgid: i32,
+        base: String,
+        filter: LdapFilter,
+        mode: SyncRequestMode,
+        cookie: Option<Vec<u8>>,
+    ) -> Self {
+        LdapMsg {
+            msgid,
+            op: LdapOp::SearchRequest(LdapSearchRequest {
+                base,
+                scope: LdapSearchScope::Subtree,
+                aliases: LdapDerefAliases::Never,
+                sizelimit: 0,
+                timelimit: 0,
+                typesonly: false,
+                filter,
+                attrs: vec![],
+            }),
+            ctrl: vec![LdapControl::SyncRequest {
+                criticality: true,
+                mode,
+                cookie,
+                reload_hint: false,
+            }],
+        }
+    }
+
+    pub fn controls_by_type(&self) -> ControlsView<'_> {
+        ControlsView { ctrl: &self.ctrl }
+    }
+
+    // The password policy response, whichever op it is attached to.
+    pub fn password_policy_response(&self) -> Option<&LdapControl> {
+        self.ctrl
+            .iter()
+            .find(|c| matches!(c, LdapControl::PasswordPolicyResponse { .. }))
+    }
+
+    // Add controls whose oid is not already present. Existing controls are
+    // kept as they are.
+    pub fn merge_controls(&mut self, extra: Vec<LdapControl>) {
+        for c in extra {
+            if !self.ctrl.iter().any(|e| e.oid() == c.oid()) {
+                self.ctrl.push(c);
+            }
+        }
+    }
+
+    // As merge_controls, but a control with the same oid replaces the
+    // existing one in place.
+    pub fn merge_controls_replace(&mut self, extra: Vec<LdapControl>) {
+        for c in extra {
+            match self.ctrl.iter_mut().find(|e| e.oid() == c.oid()) {
+                Some(e) => *e = c,
+                None => self.ctrl.push(c),
+            }
+        }
+    }
+
+    // Put the controls in the order position sensitive servers expect. The
+    // only such rule so far is that a server sort request must come before
+    // the virtual list view request, which some servers reject otherwise.
+    // Other controls keep their order.
+    pub fn order_controls(&mut self) {
+        // By oid, so a sort control held as LdapControl::Raw is moved too.
+        let sort = self.ctrl.iter().position(|c| c.oid() == SORT_REQUEST_OID);
+        let vlv = self.ctrl.iter().position(|c| c.oid() == VLV_REQUEST_OID);
+        if let (Some(sort), Some(vlv)) = (sort, vlv) {
+            if vlv < sort {
+                let c = self.ctrl.remove(sort);
+                self.ctrl.insert(vlv, c);
+            }
+        }
+    }
+
+    // A copy with every credential and password value replaced, so it can be
+    // logged or stored in full. Debug already hides these, but anything that
+    // walks the fields directly would not.
+    pub fn redacted(&self) -> LdapMsg {
+        fn redact_attrs(attrs: &mut [LdapPartialAttribute]) {
+            attrs
+                .iter_mut()
+                .filter(|a| is_password_attribute(&a.atype))
+                .for_each(|a| a.vals.iter_mut().for_each(|v| *v = redacted_value()));
+        }
+
+        let mut msg = self.clone();
+        match &mut msg.op {
+            LdapOp::BindRequest(lbr) => match &mut lbr.cred {
+                LdapBindCred::Simple(pw) => *pw = REDACTED.to_string(),
+                LdapBindCred::Sasl { credentials, .. } => {
+                    credentials.iter_mut().for_each(|c| *c = redacted_value())
+                }
+            },
+            LdapOp::BindResponse(lbr) => {
+                lbr.saslcreds.iter_mut().for_each(|c| *c = redacted_value())
+            }
+            LdapOp::SearchRequest(req) => req.filter = req.filter.redacted(),
+            LdapOp::SearchResultEntry(entry) => redact_attrs(&mut entry.attributes),
+            LdapOp::AddRequest(req) => redact_attrs(&mut req.attributes),
+            LdapOp::ModifyRequest(req) => req
+                .changes
+                .iter_mut()
+                .for_each(|c| redact_attrs(std::slice::from_mut(&mut c.modification))),
+            LdapOp::CompareRequest(req) if is_password_attribute(&req.atype) => {
+                req.assertion_value = redacted_value()
+            }
+            // As with Debug, the value of any extended operation may hold a
+            // password, such as a password modify.
+            LdapOp::ExtendedRequest(req) => {
+                req.value.iter_mut().for_each(|v| *v = redacted_value())
+            }
+            LdapOp::ExtendedResponse(res) => {
+                res.value.iter_mut().for_each(|v| *v = redacted_value())
+            }
+            _ => {}
+        }
+        msg
+    }
+
+    // Checks beyond what is needed to decode the message, for servers that
+    // want to be strict about what they accept. The message id, control and
+    // empty modify checks always apply. The attribute description checks
+    // only apply with the strict feature, and always pass without it.
+    pub fn validate_strict(&self) -> Result<(), LdapProtoError> {
+        // Message id 0 is reserved for unsolicited notifications.
+        match (self.msgid, &self.op) {
+            (i, _) if i < 0 => return Err(LdapProtoError::MessageIdInvalid),
+            (0, LdapOp::ExtendedResponse(_)) => {}
+            (0, _) => return Err(LdapProtoError::MessageIdInvalid),
+            _ => {}
+        }
+
+        // We must not process a request with a critical control we don't
+        // understand. rfc4511 4.1.11
+        if self.ctrl.iter().any(|c| {
+            matches!(
+                c,
+                LdapControl::Raw {
+                    criticality: true,
+                    ..
+                }
+            )
+        }) {
+            return Err(LdapProtoError::ControlCriticalUnsupported);
+        }
+
+        // None of the controls we know may be given more than once.
+        let mut seen = BTreeSet::new();
+        if !self.ctrl.iter().all(|c| seen.insert(c.oid())) {
+            return Err(LdapProtoError::ControlDuplicate);
+        }
+
+        match &self.op {
+            LdapOp::ModifyRequest(lmr) => lmr.validate()?,
+            LdapOp::SearchRequest(lsr) => lsr.validate()?,
+            LdapOp::AddRequest(lar) => lar
+                .attributes
+                .iter()
+                .try_for_each(LdapPartialAttribute::validate)?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // An unsolicited notification is sent by the server without a request,
+    // and must not be correlated with any outstanding operation.
+    // https://www.rfc-editor.org/rfc/rfc4511#section-4.4
+    pub fn is_unsolicited(&self) -> bool {
+        self.msgid == 0 && matches!(self.op, LdapOp::ExtendedResponse(_))
+    }
+
+    // The dns of the entries a request operates on, for audit and access
+    // control. Responses and requests without a dn give an empty vec.
+    pub fn target_dns(&self) -> Vec<&str> {
+        match &self.op {
+            LdapOp::BindRequest(LdapBindRequest { dn, .. })
+            | LdapOp::SearchRequest(LdapSearchRequest { base: dn, .. })
+            | LdapOp::AddRequest(LdapAddRequest { dn, .. })
+            | LdapOp::ModifyRequest(LdapModifyRequest { dn, .. })
+            | LdapOp::CompareRequest(LdapCompareRequest { dn, .. })
+            | LdapOp::DelRequest(dn) => vec![dn.as_str()],
+            LdapOp::ModifyDNRequest(LdapModifyDNRequest {
+                dn, new_superior, ..
+            }) => once(dn.as_str()).chain(new_superior.as_deref()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // True for both anonymous (no dn) and unauthenticated (dn, but no
+    // password) simple binds, as neither proves the identity of the client.
+    pub fn is_anonymous_bind(&self) -> bool {
+        matches!(
+            &self.op,
+            LdapOp::BindRequest(LdapBindRequest {
+                cred: LdapBindCred::Simple(pw),
+                ..
+            }) if pw.is_empty()
+        )
+    }
+
     pub fn try_from_openldap_mem_dump(bytes: &[u8]) -> Result<Self, ()> {
         let mut parser = lber::parse::Parser::new();
         let (taken, msgid_tag) = match *parser.handle(lber::Input::Element(bytes)) {
@@ -588,11 +1787,78 @@ impl LdapMsg {
     }
 }
 
+// A view over the controls of a message, so that callers can look up a
+// known control without matching over the whole vec.
+#[derive(Debug, Clone, Copy)]
+pub struct ControlsView<'a> {
+    ctrl: &'a [LdapControl],
+}
+
+impl<'a> ControlsView<'a> {
+    fn find<F>(&self, f: F) -> Option<&'a LdapControl>
+    where
+        F: Fn(&LdapControl) -> bool,
+    {
+        self.ctrl.iter().find(|c| f(c))
+    }
+
+    pub fn paged(&self) -> Option<&'a LdapControl> {
+        self.find(|c| matches!(c, LdapControl::SimplePagedResults { .. }))
+    }
+
+    pub fn sort(&self) -> Option<&'a LdapControl> {
+        self.find(|c| matches!(c, LdapControl::SortRequest { .. }))
+    }
+
+    pub fn sync_request(&self) -> Option<&'a LdapControl> {
+        self.find(|c| matches!(c, LdapControl::SyncRequest { .. }))
+    }
+
+    pub fn sync_state(&self) -> Option<&'a LdapControl> {
+        self.find(|c| matches!(c, LdapControl::SyncState { .. }))
+    }
+
+    pub fn sync_done(&self) -> Option<&'a LdapControl> {
+        self.find(|c| matches!(c, LdapControl::SyncDone { .. }))
+    }
+
+    pub fn ad_dirsync(&self) -> Option<&'a LdapControl> {
+        self.find(|c| matches!(c, LdapControl::AdDirsync { .. }))
+    }
+}
+
 impl TryFrom<StructureTag> for LdapMsg {
     type Error = ();
 
-    /// <https://tools.ietf.org/html/rfc4511#section-4.1.1>
     fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
+        LdapMsg::try_from_tag(
+            value,
+            &DecodeOptions::default(),
+            &mut DecodeProgress::default(),
+        )
+        .map_err(|_| ())
+    }
+}
+
+// How far a decode got, so the caller can trace the msgid and op kind even
+// when a later part of the message is invalid.
+#[derive(Debug, Default)]
+pub(crate) struct DecodeProgress {
+    pub msgid: Option<i32>,
+    pub op_kind: Option<LdapOpKind>,
+}
+
+impl LdapMsg {
+    // Decode a message with the limits of options. If raw_controls is set,
+    // every control is kept as LdapControl::Raw so that it re-encodes exactly
+    // as received, which a proxy needs for controls holding non-minimal
+    // integers or signatures.
+    // https://tools.ietf.org/html/rfc4511#section-4.1.1
+    pub(crate) fn try_from_tag(
+        value: StructureTag,
+        options: &DecodeOptions,
+        progress: &mut DecodeProgress,
+    ) -> Result<Self, LdapProtoError> {
         /*
          * LDAPMessage ::= SEQUENCE {
          *      messageID       MessageID,
@@ -628,8 +1894,11 @@ impl TryFrom<StructureTag> for LdapMsg {
         let mut seq = value
             .match_id(Types::Sequence as u64)
             .and_then(|t| t.expect_constructed())
-            .ok_or(())
-            .map_err(|_| error!("Message is not constructed"))?;
+            .ok_or(LdapProtoError::MessageInvalid)
+            .map_err(|e| {
+                error!("Message is not constructed");
+                e
+            })?;
 
         // seq is now a vec of the inner elements.
         let (msgid_tag, op_tag, ctrl_tag) = match seq.len() {
@@ -649,11 +1918,12 @@ impl TryFrom<StructureTag> for LdapMsg {
             }
             _ => {
                 error!("Invalid ldapmsg sequence length");
-                return Err(());
+                return Err(LdapProtoError::MessageInvalid);
             }
         };
 
-        trace!(?msgid_tag, ?op_tag, ?ctrl_tag);
+        // The op may hold credentials, so it's never logged.
+        trace!(?msgid_tag, ?ctrl_tag);
 
         // The first item should be the messageId
         let msgid = msgid_tag
@@ -666,18 +1936,35 @@ impl TryFrom<StructureTag> for LdapMsg {
             .map(|i| i as i32)
             .ok_or_else(|| {
                 error!("Invalid msgid");
-                ()
+                LdapProtoError::MessageInvalid
             })?;
+        progress.msgid = Some(msgid);
 
         let op = op_tag.ok_or_else(|| {
             error!("No ldap op present");
-            ()
+            LdapProtoError::MessageInvalid
         })?;
-        let op = LdapOp::try_from(op)?;
+        // Search result entries are checked against the limits as they are
+        // decoded.
+        let op = match op {
+            StructureTag {
+                class: TagClass::Application,
+                id: 4,
+                payload: PL::C(inner),
+            } => LdapSearchResultEntry::try_from_tag(inner, options)
+                .map(LdapOp::SearchResultEntry)?,
+            op => LdapOp::try_from(op).map_err(|_| LdapProtoError::MessageInvalid)?,
+        };
+        progress.op_kind = Some(op.kind());
 
         let ctrl = ctrl_tag
-            .and_then(|t| t.match_class(TagClass::Context))
-            .and_then(|t| t.match_id(0))
+            .filter(|t| match (t.class, t.id) {
+                (TagClass::Context, 0) => true,
+                // Some clients send the controls as a universal SET rather
+                // than [0].
+                (TagClass::Universal, id) if id == Types::Set as u64 => !cfg!(feature = "strict"),
+                _ => false,
+            })
             // So it's probably controls, decode them?
             .and_then(|t| t.expect_constructed())
             .map(|inner| {
@@ -685,9 +1972,18 @@ impl TryFrom<StructureTag> for LdapMsg {
                 inner
                     .into_iter()
                     .filter_map(|t| {
-                        TryInto::<LdapControl>::try_into(t)
+                        if options.raw_controls {
+                            return LdapControl::try_from_raw(t)
+                                .map_err(|e| error!(?e, "Failed to parse ldapcontrol"))
+                                .ok();
+                        }
+                        TryInto::<LdapControl>::try_into(t.clone())
+                            .or_else(|e| {
+                                trace!(?e, "Retaining ldapcontrol as raw");
+                                LdapControl::try_from_raw(t)
+                            })
                             .map_err(|e| {
-                                error!("Failed to parse ldapcontrol");
+                                error!(?e, "Failed to parse ldapcontrol");
                                 e
                             })
                             .ok()
@@ -773,6 +2069,14 @@ impl TryFrom<StructureTag> for LdapOp {
             (11, PL::C(inner)) => {
                 LdapResult::try_from_tag(inner).map(|(lr, _)| LdapOp::DelResponse(lr))
             }
+            (12, PL::C(inner)) => LdapModifyDNRequest::try_from(inner).map(LdapOp::ModifyDNRequest),
+            (13, PL::C(inner)) => {
+                LdapResult::try_from_tag(inner).map(|(lr, _)| LdapOp::ModifyDNResponse(lr))
+            }
+            (14, PL::C(inner)) => LdapCompareRequest::try_from(inner).map(LdapOp::CompareRequest),
+            (15, PL::C(inner)) => {
+                LdapResult::try_from_tag(inner).map(|(lr, _)| LdapOp::CompareResponse(lr))
+            }
             (16, PL::P(inner)) => ber_integer_to_i64(inner)
                 .ok_or(())
                 .map(|s| LdapOp::AbandonRequest(s as i32)),
@@ -854,6 +2158,26 @@ impl From<LdapOp> for Tag {
                 id: 11,
                 inner: lr.into(),
             }),
+            LdapOp::ModifyDNRequest(lmdr) => Tag::Sequence(Sequence {
+                class: TagClass::Application,
+                id: 12,
+                inner: lmdr.into(),
+            }),
+            LdapOp::ModifyDNResponse(lr) => Tag::Sequence(Sequence {
+                class: TagClass::Application,
+                id: 13,
+                inner: lr.into(),
+            }),
+            LdapOp::CompareRequest(lcr) => Tag::Sequence(Sequence {
+                class: TagClass::Application,
+                id: 14,
+                inner: lcr.into(),
+            }),
+            LdapOp::CompareResponse(lr) => Tag::Sequence(Sequence {
+                class: TagClass::Application,
+                id: 15,
+                inner: lr.into(),
+            }),
             LdapOp::AbandonRequest(id) => Tag::Integer(Integer {
                 class: TagClass::Application,
                 id: 16,
@@ -878,14 +2202,123 @@ impl From<LdapOp> for Tag {
     }
 }
 
-impl TryFrom<StructureTag> for LdapControl {
-    type Error = ();
+// AD stores a guid with its first three fields little endian, so the same
+// 16 bytes give a different uuid than the rfc4122 order that we use for
+// syncUUID. Use this for objectGUID values, or a SyncState from AD.
+pub fn ad_guid_from_slice(bytes: &[u8]) -> Option<Uuid> {
+    <[u8; 16]>::try_from(bytes).ok().map(Uuid::from_bytes_le)
+}
 
-    fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
+// True when the server has no more pages to return, which is signalled by
+// an empty cookie. A response without the paged control is also complete,
+// as the server did not page the results.
+pub fn is_paging_complete(controls: &[LdapControl]) -> bool {
+    controls
+        .iter()
+        .find_map(LdapControl::paged_cookie)
+        .map_or(true, |cookie| cookie.is_empty())
+}
+
+// Replaces credentials and passwords in LdapMsg::redacted and
+// LdapFilter::redacted. It has no characters that are escaped in a filter.
+pub const REDACTED: &str = "<redacted>";
+
+fn redacted_value() -> Vec<u8> {
+    REDACTED.as_bytes().to_vec()
+}
+
+// Compared without options, so userPassword;binary is a password too.
+pub(crate) fn is_password_attribute(atype: &str) -> bool {
+    let base = atype.split(';').next().unwrap_or_default();
+    [
+        "userPassword",
+        "2.5.4.35",
+        "unicodePwd",
+        "1.2.840.113556.1.4.90",
+    ]
+    .iter()
+    .any(|p| base.eq_ignore_ascii_case(p))
+}
+
+// https://datatracker.ietf.org/doc/html/draft-ietf-ldapext-ldapv3-vlv-09
+// Not decoded, so it is held as LdapControl::Raw.
+pub const VLV_REQUEST_OID: &str = "2.16.840.1.113730.3.4.9";
+
+// https://www.rfc-editor.org/rfc/rfc2891
+pub const SORT_REQUEST_OID: &str = "1.2.840.113556.1.4.473";
+
+impl LdapControl {
+    pub fn paged_cookie(&self) -> Option<&[u8]> {
+        match self {
+            LdapControl::SimplePagedResults { cookie, .. } => Some(cookie),
+            _ => None,
+        }
+    }
+
+    // The entry_uuid of a SyncState, read as an AD guid.
+    pub fn sync_state_ad_guid(&self) -> Option<Uuid> {
+        match self {
+            LdapControl::SyncState { entry_uuid, .. } => ad_guid_from_slice(entry_uuid.as_bytes()),
+            _ => None,
+        }
+    }
+
+    pub fn oid(&self) -> &str {
+        match self {
+            LdapControl::SyncRequest { .. } => "1.3.6.1.4.1.4203.1.9.1.1",
+            LdapControl::SyncState { .. } => "1.3.6.1.4.1.4203.1.9.1.2",
+            LdapControl::SyncDone { .. } => "1.3.6.1.4.1.4203.1.9.1.3",
+            LdapControl::AdDirsync { .. } => "1.2.840.113556.1.4.841",
+            LdapControl::SimplePagedResults { .. } => "1.2.840.113556.1.4.319",
+            LdapControl::SortRequest { .. } => SORT_REQUEST_OID,
+            LdapControl::PersistentSearch { .. } => "2.16.840.1.113730.3.4.3",
+            LdapControl::EntryChangeNotification { .. } => "2.16.840.1.113730.3.4.7",
+            LdapControl::Subentries { .. } => "1.3.6.1.4.1.4203.1.10.1",
+            LdapControl::AdExtendedDn { .. } => "1.2.840.113556.1.4.529",
+            LdapControl::AdNotification { .. } => "1.2.840.113556.1.4.528",
+            LdapControl::AdSdFlags { .. } => "1.2.840.113556.1.4.801",
+            LdapControl::GetEffectiveRights { .. } => "1.3.6.1.4.1.42.2.27.9.5.2",
+            LdapControl::TransactionSpecification { .. } => "1.3.6.1.1.21.2",
+            LdapControl::RelaxRules { .. } => "1.3.6.1.4.1.4203.666.5.12",
+            LdapControl::TreeDelete { .. } => "1.2.840.113556.1.4.805",
+            LdapControl::Assertion { .. } => "1.3.6.1.1.12",
+            LdapControl::PasswordPolicyRequest { .. }
+            | LdapControl::PasswordPolicyResponse { .. } => "1.3.6.1.4.1.42.2.27.8.5.1",
+            LdapControl::AccountUsableRequest { .. } | LdapControl::AccountUsableResponse(_) => {
+                "1.3.6.1.4.1.42.2.27.9.5.8"
+            }
+            LdapControl::MatchedValues { .. } => "1.2.826.0.1.3344810.2.3",
+            LdapControl::Raw { oid, .. } => oid.as_str(),
+        }
+    }
+
+    // The complete control SEQUENCE as it is sent on the wire, for schemes
+    // that sign the exact bytes of a control.
+    pub fn to_ber(&self) -> Vec<u8> {
+        let tag: Tag = self.clone().into();
+        let mut bytes = BytesMut::new();
+        // Writing to a BytesMut can't fail.
+        let _ = lber_write::encode_into(&mut bytes, tag.into_structure());
+        bytes.to_vec()
+    }
+
+    // Split a control into its oid, and the criticality and value tags if
+    // present.
+    fn try_into_parts(
+        value: StructureTag,
+    ) -> Result<(String, Option<StructureTag>, Option<StructureTag>), LdapProtoError> {
         let mut seq = value
             .match_id(Types::Sequence as u64)
             .and_then(|t| t.expect_constructed())
-            .ok_or(())?;
+            .ok_or(LdapProtoError::ControlBerInvalid)?;
+
+        if seq.len() > 3 {
+            if cfg!(feature = "strict") {
+                return Err(LdapProtoError::MalformedControl);
+            }
+            warn!(len = %seq.len(), "control has more than 3 elements, ignoring the rest");
+            seq.truncate(3);
+        }
 
         // We destructure in reverse order due to how vec in rust
         // works.
@@ -896,9 +2329,18 @@ impl TryFrom<StructureTag> for LdapControl {
                 let o = seq.pop();
                 (o, c, v)
             }
+            // The second element is either the criticality or the value,
+            // which are told apart by their tag.
             2 => {
-                let v = seq.pop();
-                let c = None;
+                let t = seq.pop();
+                let (c, v) = match t {
+                    Some(StructureTag {
+                        class: TagClass::Universal,
+                        id,
+                        ..
+                    }) if id == Types::Boolean as u64 => (t, None),
+                    _ => (None, t),
+                };
                 let o = seq.pop();
                 (o, c, v)
             }
@@ -908,7 +2350,7 @@ impl TryFrom<StructureTag> for LdapControl {
                 let o = seq.pop();
                 (o, c, v)
             }
-            _ => return Err(()),
+            _ => return Err(LdapProtoError::ControlBerInvalid),
         };
 
         // trace!(?oid_tag, ?criticality_tag, ?value_tag);
@@ -919,7 +2361,41 @@ impl TryFrom<StructureTag> for LdapControl {
             .and_then(|t| t.match_id(Types::OctetString as u64))
             .and_then(|t| t.expect_primitive())
             .and_then(|bv| String::from_utf8(bv).ok())
-            .ok_or(())?;
+            .ok_or(LdapProtoError::ControlBerInvalid)?;
+
+        Ok((oid, criticality_tag, value_tag))
+    }
+
+    // Decode the control without interpreting its value. This is used when
+    // the oid is unknown, or the value could not be decoded, so that the
+    // control is not lost.
+    pub(crate) fn try_from_raw(value: StructureTag) -> Result<Self, LdapProtoError> {
+        let (oid, criticality_tag, value_tag) = LdapControl::try_into_parts(value)?;
+
+        let criticality = criticality_tag
+            .and_then(|t| t.match_class(TagClass::Universal))
+            .and_then(|t| t.match_id(Types::Boolean as u64))
+            .and_then(|t| t.expect_primitive())
+            .and_then(ber_bool_to_bool)
+            .unwrap_or(false);
+
+        let value = value_tag
+            .map(|t| octet_string_bytes(t).ok_or(LdapProtoError::ControlBerInvalid))
+            .transpose()?;
+
+        Ok(LdapControl::Raw {
+            oid,
+            criticality,
+            value,
+        })
+    }
+}
+
+impl TryFrom<StructureTag> for LdapControl {
+    type Error = LdapProtoError;
+
+    fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
+        let (oid, criticality_tag, value_tag) = LdapControl::try_into_parts(value)?;
 
         match oid.as_str() {
             "1.3.6.1.4.1.4203.1.9.1.1" => {
@@ -931,21 +2407,7 @@ impl TryFrom<StructureTag> for LdapControl {
                     .and_then(ber_bool_to_bool)
                     .unwrap_or(false);
 
-                let value_ber = value_tag
-                    .and_then(|t| t.match_class(TagClass::Universal))
-                    .and_then(|t| t.match_id(Types::OctetString as u64))
-                    .and_then(|t| t.expect_primitive())
-                    .ok_or(())?;
-
-                let mut parser = Parser::new();
-                let (_size, value) = match *parser.handle(Input::Element(&value_ber)) {
-                    ConsumerState::Done(size, ref msg) => (size, msg),
-                    _ => return Err(()),
-                };
-
-                let mut value = value.clone().expect_constructed().ok_or(())?;
-
-                value.reverse();
+                let mut value = parse_control_value(value_tag)?;
 
                 let mode = value
                     .pop()
@@ -958,7 +2420,7 @@ impl TryFrom<StructureTag> for LdapControl {
                         3 => Some(SyncRequestMode::RefreshAndPersist),
                         _ => None,
                     })
-                    .ok_or(())?;
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
 
                 let cookie = value
                     .pop()
@@ -986,21 +2448,7 @@ impl TryFrom<StructureTag> for LdapControl {
 
                 //criticality is ignored.
 
-                let value_ber = value_tag
-                    .and_then(|t| t.match_class(TagClass::Universal))
-                    .and_then(|t| t.match_id(Types::OctetString as u64))
-                    .and_then(|t| t.expect_primitive())
-                    .ok_or(())?;
-
-                let mut parser = Parser::new();
-                let (_size, value) = match *parser.handle(Input::Element(&value_ber)) {
-                    ConsumerState::Done(size, ref msg) => (size, msg),
-                    _ => return Err(()),
-                };
-
-                let mut value = value.clone().expect_constructed().ok_or(())?;
-
-                value.reverse();
+                let mut value = parse_control_value(value_tag)?;
 
                 let state = value
                     .pop()
@@ -1015,18 +2463,18 @@ impl TryFrom<StructureTag> for LdapControl {
                         3 => Some(SyncStateValue::Delete),
                         _ => None,
                     })
-                    .ok_or(())?;
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
 
                 let entry_uuid = value
                     .pop()
                     .and_then(|t| t.match_class(TagClass::Universal))
                     .and_then(|t| t.match_id(Types::OctetString as u64))
                     .and_then(|t| t.expect_primitive())
-                    .ok_or(())
+                    .ok_or(LdapProtoError::ControlValueInvalid)
                     .and_then(|v| {
                         Uuid::from_slice(&v).map_err(|_| {
                             error!("Invalid syncUUID");
-                            ()
+                            LdapProtoError::ControlValueInvalid
                         })
                     })?;
 
@@ -1046,21 +2494,7 @@ impl TryFrom<StructureTag> for LdapControl {
                 // parse as sync done control
                 // criticality is ignored.
 
-                let value_ber = value_tag
-                    .and_then(|t| t.match_class(TagClass::Universal))
-                    .and_then(|t| t.match_id(Types::OctetString as u64))
-                    .and_then(|t| t.expect_primitive())
-                    .ok_or(())?;
-
-                let mut parser = Parser::new();
-                let (_size, value) = match *parser.handle(Input::Element(&value_ber)) {
-                    ConsumerState::Done(size, ref msg) => (size, msg),
-                    _ => return Err(()),
-                };
-
-                let mut value = value.clone().expect_constructed().ok_or(())?;
-
-                value.reverse();
+                let mut value = parse_control_value(value_tag)?;
 
                 let cookie = value
                     .pop()
@@ -1082,29 +2516,15 @@ impl TryFrom<StructureTag> for LdapControl {
                 })
             }
             "1.2.840.113556.1.4.841" => {
-                let value_ber = value_tag
-                    .and_then(|t| t.match_class(TagClass::Universal))
-                    .and_then(|t| t.match_id(Types::OctetString as u64))
-                    .and_then(|t| t.expect_primitive())
-                    .ok_or(())?;
-
-                let mut parser = Parser::new();
-                let (_size, value) = match *parser.handle(Input::Element(&value_ber)) {
-                    ConsumerState::Done(size, ref msg) => (size, msg),
-                    _ => return Err(()),
-                };
-
-                let mut value = value.clone().expect_constructed().ok_or(())?;
-
-                value.reverse();
+                let mut value = parse_control_value(value_tag)?;
 
                 let flags = value
                     .pop()
                     .and_then(|t| t.match_class(TagClass::Universal))
                     .and_then(|t| t.match_id(Types::Integer as u64))
                     .and_then(|t| t.expect_primitive())
-                    .and_then(ber_integer_to_i64)
-                    .ok_or(())?;
+                    .and_then(|bv| ber_integer_to_i32(&bv))
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
 
                 let max_bytes = value
                     .pop()
@@ -1112,8 +2532,10 @@ impl TryFrom<StructureTag> for LdapControl {
                     .and_then(|t| t.match_id(Types::Integer as u64))
                     .and_then(|t| t.expect_primitive())
                     .and_then(ber_integer_to_i64)
-                    .ok_or(())?;
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
 
+                // None is encoded as an empty cookie, so it decodes as
+                // Some(vec![]).
                 let cookie = value
                     .pop()
                     .and_then(|t| t.match_class(TagClass::Universal))
@@ -1126,173 +2548,971 @@ impl TryFrom<StructureTag> for LdapControl {
                     cookie,
                 })
             }
-            o => {
-                error!(%o, "Unsupported control oid");
-                Err(())
-            }
-        }
-    }
-}
+            "1.2.840.113556.1.4.319" => {
+                let mut value = parse_control_value(value_tag)?;
 
-impl From<LdapControl> for Tag {
-    fn from(value: LdapControl) -> Tag {
-        let (oid, crit, inner_tag) = match value {
-            LdapControl::SyncRequest {
-                criticality,
-                mode,
-                cookie,
-                reload_hint,
-            } => {
-                let inner: Vec<_> = vec![
-                    Some(Tag::Enumerated(Enumerated {
-                        inner: mode as i64,
-                        ..Default::default()
-                    })),
-                    cookie.map(|c| {
-                        Tag::OctetString(OctetString {
-                            inner: c,
-                            ..Default::default()
-                        })
-                    }),
-                    if reload_hint {
-                        Some(Tag::Boolean(Boolean {
-                            inner: true,
-                            ..Default::default()
-                        }))
-                    } else {
-                        None
-                    },
-                ];
+                let size = value
+                    .pop()
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Integer as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_integer_to_i64)
+                    .map(|v| v as i32)
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
 
-                (
-                    "1.3.6.1.4.1.4203.1.9.1.1",
-                    criticality,
-                    Some(Tag::Sequence(Sequence {
-                        inner: inner.into_iter().flatten().collect(),
-                        ..Default::default()
-                    })),
-                )
-            }
-            LdapControl::SyncState {
-                state,
-                entry_uuid,
-                cookie,
-            } => {
-                let inner: Vec<_> = vec![
-                    Some(Tag::Enumerated(Enumerated {
-                        inner: state as i64,
-                        ..Default::default()
-                    })),
-                    Some(Tag::OctetString(OctetString {
-                        inner: entry_uuid.as_bytes().to_vec(),
-                        ..Default::default()
-                    })),
-                    cookie.map(|c| {
-                        Tag::OctetString(OctetString {
-                            inner: c,
-                            ..Default::default()
-                        })
-                    }),
-                ];
+                let cookie = value
+                    .pop()
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::OctetString as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
 
-                (
-                    "1.3.6.1.4.1.4203.1.9.1.2",
-                    false,
-                    Some(Tag::Sequence(Sequence {
-                        inner: inner.into_iter().flatten().collect(),
-                        ..Default::default()
-                    })),
-                )
+                Ok(LdapControl::SimplePagedResults { size, cookie })
             }
-            LdapControl::SyncDone {
-                cookie,
-                refresh_deletes,
-            } => {
-                let inner: Vec<_> = vec![
-                    cookie.map(|c| {
-                        Tag::OctetString(OctetString {
-                            inner: c,
-                            ..Default::default()
-                        })
-                    }),
-                    if refresh_deletes {
-                        Some(Tag::Boolean(Boolean {
-                            inner: true,
-                            ..Default::default()
-                        }))
-                    } else {
-                        None
-                    },
-                ];
+            "1.2.840.113556.1.4.473" => {
+                let criticality = criticality_tag
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Boolean as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_bool_to_bool)
+                    .unwrap_or(false);
 
-                (
-                    "1.3.6.1.4.1.4203.1.9.1.3",
-                    false,
-                    Some(Tag::Sequence(Sequence {
-                        inner: inner.into_iter().flatten().collect(),
-                        ..Default::default()
-                    })),
-                )
-            }
-            LdapControl::AdDirsync {
-                flags,
-                max_bytes,
-                cookie,
-            } => {
-                let criticality = true;
-                let inner: Vec<_> = vec![
-                    Tag::Integer(Integer {
-                        inner: flags,
-                        ..Default::default()
-                    }),
-                    Tag::Integer(Integer {
-                        inner: max_bytes,
-                        ..Default::default()
-                    }),
-                    Tag::OctetString(OctetString {
-                        inner: cookie.unwrap_or_default(),
-                        ..Default::default()
-                    }),
-                ];
+                let value = parse_control_value(value_tag)?;
 
-                (
-                    "1.2.840.113556.1.4.841",
-                    criticality,
-                    Some(Tag::Sequence(Sequence {
-                        inner,
-                        ..Default::default()
-                    })),
-                )
-            }
-        };
+                // The value was reversed for popping, but here we want the
+                // keys in their original order.
+                let keys = value
+                    .into_iter()
+                    .rev()
+                    .map(|t| {
+                        let mut key = t
+                            .match_class(TagClass::Universal)
+                            .and_then(|t| t.match_id(Types::Sequence as u64))
+                            .and_then(|t| t.expect_constructed())
+                            .ok_or(LdapProtoError::ControlValueInvalid)?;
 
-        let mut inner = Vec::with_capacity(3);
+                        key.reverse();
 
-        inner.push(Tag::OctetString(OctetString {
-            inner: Vec::from(oid),
-            ..Default::default()
-        }));
-        if crit {
-            inner.push(Tag::Boolean(Boolean {
-                inner: true,
-                ..Default::default()
-            }));
+                        let attribute_type = key
+                            .pop()
+                            .and_then(|t| t.match_class(TagClass::Universal))
+                            .and_then(|t| t.match_id(Types::OctetString as u64))
+                            .and_then(|t| t.expect_primitive())
+                            .and_then(|bv| String::from_utf8(bv).ok())
+                            .ok_or(LdapProtoError::ControlValueInvalid)?;
+
+                        let mut ordering_rule = None;
+                        let mut reverse_order = false;
+
+                        for t in key.into_iter().rev() {
+                            match (t.class, t.id) {
+                                (TagClass::Context, 0) => {
+                                    ordering_rule = t
+                                        .expect_primitive()
+                                        .and_then(|bv| String::from_utf8(bv).ok())
+                                        .map(Some)
+                                        .ok_or(LdapProtoError::ControlValueInvalid)?;
+                                }
+                                (TagClass::Context, 1) => {
+                                    reverse_order = t
+                                        .expect_primitive()
+                                        .and_then(ber_bool_to_bool)
+                                        .ok_or(LdapProtoError::ControlValueInvalid)?;
+                                }
+                                _ => return Err(LdapProtoError::ControlValueInvalid),
+                            }
+                        }
+
+                        Ok(LdapSortKey {
+                            attribute_type,
+                            ordering_rule,
+                            reverse_order,
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(LdapControl::SortRequest { criticality, keys })
+            }
+            "2.16.840.1.113730.3.4.3" => {
+                // criticality is ignored, as we always send this as critical.
+                let mut value = parse_control_value(value_tag)?;
+
+                let change_types = value
+                    .pop()
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Integer as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_integer_to_i64)
+                    .map(|v| v as i32)
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
+
+                let changes_only = value
+                    .pop()
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Boolean as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_bool_to_bool)
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
+
+                let return_ecs = value
+                    .pop()
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Boolean as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_bool_to_bool)
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
+
+                Ok(LdapControl::PersistentSearch {
+                    change_types,
+                    changes_only,
+                    return_ecs,
+                })
+            }
+            "2.16.840.1.113730.3.4.7" => {
+                // criticality is ignored.
+                let mut value = parse_control_value(value_tag)?;
+
+                let change_type = value
+                    .pop()
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Enumerated as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_integer_to_i64)
+                    .and_then(|v| match v {
+                        1 => Some(LdapEntryChangeType::Add),
+                        2 => Some(LdapEntryChangeType::Delete),
+                        4 => Some(LdapEntryChangeType::Modify),
+                        8 => Some(LdapEntryChangeType::ModDn),
+                        _ => None,
+                    })
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
+
+                // Both of the remaining fields are optional, so we have to
+                // check which one we have.
+                let mut previous_dn = None;
+                let mut change_number = None;
+
+                while let Some(t) = value.pop() {
+                    match (t.class, t.id) {
+                        (TagClass::Universal, id) if id == Types::OctetString as u64 => {
+                            previous_dn = t
+                                .expect_primitive()
+                                .and_then(|bv| String::from_utf8(bv).ok())
+                                .map(Some)
+                                .ok_or(LdapProtoError::ControlValueInvalid)?;
+                        }
+                        (TagClass::Universal, id) if id == Types::Integer as u64 => {
+                            change_number = t
+                                .expect_primitive()
+                                .and_then(ber_integer_to_i64)
+                                .map(Some)
+                                .ok_or(LdapProtoError::ControlValueInvalid)?;
+                        }
+                        _ => return Err(LdapProtoError::ControlValueInvalid),
+                    }
+                }
+
+                Ok(LdapControl::EntryChangeNotification {
+                    change_type,
+                    previous_dn,
+                    change_number,
+                })
+            }
+            "1.3.6.1.4.1.4203.1.10.1" => {
+                let criticality = criticality_tag
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Boolean as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_bool_to_bool)
+                    .unwrap_or(false);
+
+                let visibility = parse_control_value_primitive(value_tag)?
+                    .match_class(TagClass::Universal)
+                    .and_then(|t| t.match_id(Types::Boolean as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_bool_to_bool)
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
+
+                Ok(LdapControl::Subentries {
+                    criticality,
+                    visibility,
+                })
+            }
+            "1.2.840.113556.1.4.529" => {
+                let criticality = criticality_tag
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Boolean as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_bool_to_bool)
+                    .unwrap_or(false);
+
+                // The value is optional, and defaults to 0. Windows expects
+                // SEQUENCE { INTEGER }, but some clients send the INTEGER
+                // alone.
+                let flag = match value_tag {
+                    None => 0,
+                    Some(t) => parse_control_value_primitive(Some(t.clone()))
+                        .or_else(|_| {
+                            parse_control_value(Some(t))?
+                                .pop()
+                                .ok_or(LdapProtoError::ControlValueInvalid)
+                        })?
+                        .match_class(TagClass::Universal)
+                        .and_then(|t| t.match_id(Types::Integer as u64))
+                        .and_then(|t| t.expect_primitive())
+                        .and_then(ber_integer_to_i64)
+                        .ok_or(LdapProtoError::ControlValueInvalid)?,
+                };
+
+                Ok(LdapControl::AdExtendedDn { criticality, flag })
+            }
+            "1.2.840.113556.1.4.801" => {
+                let flags = parse_control_value(value_tag)?
+                    .pop()
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Integer as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_integer_to_i64)
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
+
+                Ok(LdapControl::AdSdFlags {
+                    flags: i32::try_from(flags).map_err(|_| LdapProtoError::ControlValueInvalid)?,
+                })
+            }
+            "1.3.6.1.4.1.42.2.27.9.5.2" => {
+                let mut value = parse_control_value(value_tag)?;
+
+                let authzid = value
+                    .pop()
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::OctetString as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(|bv| String::from_utf8(bv).ok())
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
+
+                // The attribute list may be omitted.
+                let attributes = value
+                    .pop()
+                    .map(|t| {
+                        t.match_class(TagClass::Universal)
+                            .and_then(|t| t.match_id(Types::Sequence as u64))
+                            .and_then(|t| t.expect_constructed())
+                            .and_then(|inner| {
+                                inner
+                                    .into_iter()
+                                    .map(|t| {
+                                        t.match_class(TagClass::Universal)
+                                            .and_then(|t| t.match_id(Types::OctetString as u64))
+                                            .and_then(|t| t.expect_primitive())
+                                            .and_then(|bv| String::from_utf8(bv).ok())
+                                    })
+                                    .collect::<Option<Vec<_>>>()
+                            })
+                            .ok_or(LdapProtoError::ControlValueInvalid)
+                    })
+                    .transpose()?
+                    .unwrap_or_default();
+
+                Ok(LdapControl::GetEffectiveRights {
+                    authzid,
+                    attributes,
+                })
+            }
+            "1.3.6.1.1.21.2" => {
+                // The value is the transaction identifier itself, not BER.
+                let identifier = value_tag
+                    .ok_or(LdapProtoError::ControlValueMissing)?
+                    .match_class(TagClass::Universal)
+                    .and_then(|t| t.match_id(Types::OctetString as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .ok_or(LdapProtoError::ControlBerInvalid)?;
+
+                Ok(LdapControl::TransactionSpecification { identifier })
+            }
+            "1.3.6.1.4.1.4203.666.5.12" => {
+                if value_tag.is_some() {
+                    return Err(LdapProtoError::ControlValueInvalid);
+                }
+
+                let criticality = criticality_tag
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Boolean as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_bool_to_bool)
+                    .unwrap_or(false);
+
+                Ok(LdapControl::RelaxRules { criticality })
+            }
+            "1.2.840.113556.1.4.805" => {
+                if value_tag.is_some() {
+                    return Err(LdapProtoError::ControlValueInvalid);
+                }
+
+                let criticality = criticality_tag
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Boolean as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_bool_to_bool)
+                    .unwrap_or(false);
+
+                Ok(LdapControl::TreeDelete { criticality })
+            }
+            "1.2.840.113556.1.4.528" => {
+                if value_tag.is_some() {
+                    return Err(LdapProtoError::ControlValueInvalid);
+                }
+
+                let criticality = criticality_tag
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Boolean as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_bool_to_bool)
+                    .unwrap_or(false);
+
+                Ok(LdapControl::AdNotification { criticality })
+            }
+            "1.3.6.1.4.1.42.2.27.9.5.8" => {
+                // The same oid is used both ways. Only the response has a value.
+                if value_tag.is_none() {
+                    let criticality = criticality_tag
+                        .and_then(|t| t.match_class(TagClass::Universal))
+                        .and_then(|t| t.match_id(Types::Boolean as u64))
+                        .and_then(|t| t.expect_primitive())
+                        .and_then(ber_bool_to_bool)
+                        .unwrap_or(false);
+                    return Ok(LdapControl::AccountUsableRequest { criticality });
+                }
+
+                let value = parse_control_value_tag(value_tag)?
+                    .match_class(TagClass::Context)
+                    .ok_or(LdapProtoError::ControlValueInvalid)?;
+
+                let usability = match (value.id, value.payload) {
+                    (0, PL::P(bv)) => LdapAccountUsability::Available {
+                        seconds_before_expiration: ber_integer_to_i64(bv)
+                            .ok_or(LdapProtoError::ControlValueInvalid)?,
+                    },
+                    (1, PL::C(inner)) => {
+                        let mut inactive = false;
+                        let mut reset = false;
+                        let mut expired = false;
+                        let mut remaining_grace = None;
+                        let mut seconds_before_unlock = None;
+
+                        for t in inner {
+                            let id = t.id;
+                            let bv = t
+                                .match_class(TagClass::Context)
+                                .and_then(|t| t.expect_primitive())
+                                .ok_or(LdapProtoError::ControlValueInvalid)?;
+                            match id {
+                                0 => inactive = ber_bool_to_bool(bv).unwrap_or(false),
+                                1 => reset = ber_bool_to_bool(bv).unwrap_or(false),
+                                2 => expired = ber_bool_to_bool(bv).unwrap_or(false),
+                                3 => remaining_grace = ber_integer_to_i64(bv),
+                                4 => seconds_before_unlock = ber_integer_to_i64(bv),
+                                _ => return Err(LdapProtoError::ControlValueInvalid),
+                            }
+                        }
+
+                        LdapAccountUsability::NotAvailable {
+                            inactive,
+                            reset,
+                            expired,
+                            remaining_grace,
+                            seconds_before_unlock,
+                        }
+                    }
+                    _ => return Err(LdapProtoError::ControlValueInvalid),
+                };
+
+                Ok(LdapControl::AccountUsableResponse(usability))
+            }
+            "1.3.6.1.4.1.42.2.27.8.5.1" => {
+                // The same oid is used both ways. Only the response has a value.
+                if value_tag.is_none() {
+                    let criticality = criticality_tag
+                        .and_then(|t| t.match_class(TagClass::Universal))
+                        .and_then(|t| t.match_id(Types::Boolean as u64))
+                        .and_then(|t| t.expect_primitive())
+                        .and_then(ber_bool_to_bool)
+                        .unwrap_or(false);
+                    return Ok(LdapControl::PasswordPolicyRequest { criticality });
+                }
+
+                let mut warning = None;
+                let mut error = None;
+
+                for t in parse_control_value(value_tag)? {
+                    match (t.class, t.id, t.payload) {
+                        // The warning is a CHOICE, so is explicitly tagged.
+                        (TagClass::Context, 0, PL::C(mut inner)) if inner.len() == 1 => {
+                            let w = inner.pop().ok_or(LdapProtoError::ControlValueInvalid)?;
+                            let id = w.id;
+                            let v = w
+                                .match_class(TagClass::Context)
+                                .and_then(|t| t.expect_primitive())
+                                .and_then(ber_integer_to_i64)
+                                .ok_or(LdapProtoError::ControlValueInvalid)?;
+                            warning = match id {
+                                0 => Some(LdapPasswordPolicyWarning::TimeBeforeExpiration(v)),
+                                1 => Some(LdapPasswordPolicyWarning::GraceAuthNsRemaining(v)),
+                                _ => return Err(LdapProtoError::ControlValueInvalid),
+                            };
+                        }
+                        (TagClass::Context, 1, PL::P(bv)) => {
+                            error = ber_integer_to_i64(bv)
+                                .and_then(|v| LdapPasswordPolicyError::try_from(v).ok())
+                                .map(Some)
+                                .ok_or(LdapProtoError::ControlValueInvalid)?;
+                        }
+                        _ => return Err(LdapProtoError::ControlValueInvalid),
+                    }
+                }
+
+                Ok(LdapControl::PasswordPolicyResponse { warning, error })
+            }
+            "1.3.6.1.1.12" => {
+                let criticality = criticality_tag
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Boolean as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_bool_to_bool)
+                    .unwrap_or(false);
+
+                let filter = decode_filter_value(parse_control_value_tag(value_tag)?)?;
+
+                Ok(LdapControl::Assertion {
+                    criticality,
+                    filter,
+                })
+            }
+            "1.2.826.0.1.3344810.2.3" => {
+                let criticality = criticality_tag
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::Boolean as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(ber_bool_to_bool)
+                    .unwrap_or(false);
+
+                let mut value = parse_control_value(value_tag)?;
+                value.reverse();
+
+                let filters = value
+                    .into_iter()
+                    .map(|t| match decode_filter_value(t)? {
+                        LdapFilter::And(_) | LdapFilter::Or(_) | LdapFilter::Not(_) => {
+                            Err(LdapProtoError::ControlValueInvalid)
+                        }
+                        f => Ok(f),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                Ok(LdapControl::MatchedValues {
+                    criticality,
+                    filters,
+                })
+            }
+            o => {
+                error!(%o, "Unsupported control oid");
+                Err(LdapProtoError::ControlUnsupported)
+            }
         }
+    }
+}
+
+impl From<LdapControl> for Tag {
+    fn from(value: LdapControl) -> Tag {
+        let (oid, crit, inner_tag) = match value {
+            LdapControl::Raw {
+                oid,
+                criticality,
+                value,
+            } => return control_tag(&oid, criticality, value),
+            LdapControl::TransactionSpecification { identifier } => {
+                return control_tag("1.3.6.1.1.21.2", true, Some(identifier))
+            }
+            LdapControl::SyncRequest {
+                criticality,
+                mode,
+                cookie,
+                reload_hint,
+            } => {
+                let inner: Vec<_> = vec![
+                    Some(Tag::Enumerated(Enumerated {
+                        inner: mode as i64,
+                        ..Default::default()
+                    })),
+                    cookie.map(|c| {
+                        Tag::OctetString(OctetString {
+                            inner: c,
+                            ..Default::default()
+                        })
+                    }),
+                    if reload_hint {
+                        Some(Tag::Boolean(Boolean {
+                            inner: true,
+                            ..Default::default()
+                        }))
+                    } else {
+                        None
+                    },
+                ];
+
+                (
+                    "1.3.6.1.4.1.4203.1.9.1.1",
+                    criticality,
+                    Some(Tag::Sequence(Sequence {
+                        inner: inner.into_iter().flatten().collect(),
+                        ..Default::default()
+                    })),
+                )
+            }
+            LdapControl::SyncState {
+                state,
+                entry_uuid,
+                cookie,
+            } => {
+                let inner: Vec<_> = vec![
+                    Some(Tag::Enumerated(Enumerated {
+                        inner: state as i64,
+                        ..Default::default()
+                    })),
+                    Some(Tag::OctetString(OctetString {
+                        inner: entry_uuid.as_bytes().to_vec(),
+                        ..Default::default()
+                    })),
+                    cookie.map(|c| {
+                        Tag::OctetString(OctetString {
+                            inner: c,
+                            ..Default::default()
+                        })
+                    }),
+                ];
+
+                (
+                    "1.3.6.1.4.1.4203.1.9.1.2",
+                    false,
+                    Some(Tag::Sequence(Sequence {
+                        inner: inner.into_iter().flatten().collect(),
+                        ..Default::default()
+                    })),
+                )
+            }
+            LdapControl::SyncDone {
+                cookie,
+                refresh_deletes,
+            } => {
+                let inner: Vec<_> = vec![
+                    cookie.map(|c| {
+                        Tag::OctetString(OctetString {
+                            inner: c,
+                            ..Default::default()
+                        })
+                    }),
+                    if refresh_deletes {
+                        Some(Tag::Boolean(Boolean {
+                            inner: true,
+                            ..Default::default()
+                        }))
+                    } else {
+                        None
+                    },
+                ];
+
+                (
+                    "1.3.6.1.4.1.4203.1.9.1.3",
+                    false,
+                    Some(Tag::Sequence(Sequence {
+                        inner: inner.into_iter().flatten().collect(),
+                        ..Default::default()
+                    })),
+                )
+            }
+            LdapControl::AdDirsync {
+                flags,
+                max_bytes,
+                cookie,
+            } => {
+                let criticality = true;
+                let inner: Vec<_> = vec![
+                    Tag::Integer(Integer {
+                        inner: flags.into(),
+                        ..Default::default()
+                    }),
+                    Tag::Integer(Integer {
+                        inner: max_bytes,
+                        ..Default::default()
+                    }),
+                    Tag::OctetString(OctetString {
+                        inner: cookie.unwrap_or_default(),
+                        ..Default::default()
+                    }),
+                ];
+
+                (
+                    "1.2.840.113556.1.4.841",
+                    criticality,
+                    Some(Tag::Sequence(Sequence {
+                        inner,
+                        ..Default::default()
+                    })),
+                )
+            }
+            LdapControl::SimplePagedResults { size, cookie } => {
+                let inner: Vec<_> = vec![
+                    Tag::Integer(Integer {
+                        inner: size as i64,
+                        ..Default::default()
+                    }),
+                    Tag::OctetString(OctetString {
+                        inner: cookie,
+                        ..Default::default()
+                    }),
+                ];
+
+                (
+                    "1.2.840.113556.1.4.319",
+                    false,
+                    Some(Tag::Sequence(Sequence {
+                        inner,
+                        ..Default::default()
+                    })),
+                )
+            }
+            LdapControl::SortRequest { criticality, keys } => {
+                let inner: Vec<_> = keys
+                    .into_iter()
+                    .map(|key| {
+                        let LdapSortKey {
+                            attribute_type,
+                            ordering_rule,
+                            reverse_order,
+                        } = key;
+
+                        let inner: Vec<_> = vec![
+                            Some(Tag::OctetString(OctetString {
+                                inner: Vec::from(attribute_type),
+                                ..Default::default()
+                            })),
+                            ordering_rule.map(|r| {
+                                Tag::OctetString(OctetString {
+                                    class: TagClass::Context,
+                                    id: 0,
+                                    inner: Vec::from(r),
+                                })
+                            }),
+                            if reverse_order {
+                                Some(Tag::Boolean(Boolean {
+                                    class: TagClass::Context,
+                                    id: 1,
+                                    inner: true,
+                                }))
+                            } else {
+                                None
+                            },
+                        ];
+
+                        Tag::Sequence(Sequence {
+                            inner: inner.into_iter().flatten().collect(),
+                            ..Default::default()
+                        })
+                    })
+                    .collect();
+
+                (
+                    "1.2.840.113556.1.4.473",
+                    criticality,
+                    Some(Tag::Sequence(Sequence {
+                        inner,
+                        ..Default::default()
+                    })),
+                )
+            }
+            LdapControl::PersistentSearch {
+                change_types,
+                changes_only,
+                return_ecs,
+            } => {
+                let inner: Vec<_> = vec![
+                    Tag::Integer(Integer {
+                        inner: change_types as i64,
+                        ..Default::default()
+                    }),
+                    Tag::Boolean(Boolean {
+                        inner: changes_only,
+                        ..Default::default()
+                    }),
+                    Tag::Boolean(Boolean {
+                        inner: return_ecs,
+                        ..Default::default()
+                    }),
+                ];
+
+                (
+                    "2.16.840.1.113730.3.4.3",
+                    true,
+                    Some(Tag::Sequence(Sequence {
+                        inner,
+                        ..Default::default()
+                    })),
+                )
+            }
+            LdapControl::EntryChangeNotification {
+                change_type,
+                previous_dn,
+                change_number,
+            } => {
+                let inner: Vec<_> = vec![
+                    Some(Tag::Enumerated(Enumerated {
+                        inner: change_type as i64,
+                        ..Default::default()
+                    })),
+                    previous_dn.map(|dn| {
+                        Tag::OctetString(OctetString {
+                            inner: Vec::from(dn),
+                            ..Default::default()
+                        })
+                    }),
+                    change_number.map(|n| {
+                        Tag::Integer(Integer {
+                            inner: n,
+                            ..Default::default()
+                        })
+                    }),
+                ];
+
+                (
+                    "2.16.840.1.113730.3.4.7",
+                    false,
+                    Some(Tag::Sequence(Sequence {
+                        inner: inner.into_iter().flatten().collect(),
+                        ..Default::default()
+                    })),
+                )
+            }
+            LdapControl::Subentries {
+                criticality,
+                visibility,
+            } => (
+                "1.3.6.1.4.1.4203.1.10.1",
+                criticality,
+                Some(Tag::Boolean(Boolean {
+                    inner: visibility,
+                    ..Default::default()
+                })),
+            ),
+            LdapControl::RelaxRules { criticality } => {
+                ("1.3.6.1.4.1.4203.666.5.12", criticality, None)
+            }
+            LdapControl::TreeDelete { criticality } => {
+                ("1.2.840.113556.1.4.805", criticality, None)
+            }
+            LdapControl::AdNotification { criticality } => {
+                ("1.2.840.113556.1.4.528", criticality, None)
+            }
+            LdapControl::PasswordPolicyRequest { criticality } => {
+                ("1.3.6.1.4.1.42.2.27.8.5.1", criticality, None)
+            }
+            LdapControl::PasswordPolicyResponse { warning, error } => {
+                let inner = vec![
+                    warning.map(|w| {
+                        let (id, inner) = match w {
+                            LdapPasswordPolicyWarning::TimeBeforeExpiration(v) => (0, v),
+                            LdapPasswordPolicyWarning::GraceAuthNsRemaining(v) => (1, v),
+                        };
+                        Tag::Sequence(Sequence {
+                            class: TagClass::Context,
+                            id: 0,
+                            inner: vec![Tag::Integer(Integer {
+                                class: TagClass::Context,
+                                id,
+                                inner,
+                            })],
+                        })
+                    }),
+                    error.map(|e| {
+                        Tag::Enumerated(Enumerated {
+                            class: TagClass::Context,
+                            id: 1,
+                            inner: e as i64,
+                        })
+                    }),
+                ];
+                (
+                    "1.3.6.1.4.1.42.2.27.8.5.1",
+                    false,
+                    Some(Tag::Sequence(Sequence {
+                        inner: inner.into_iter().flatten().collect(),
+                        ..Default::default()
+                    })),
+                )
+            }
+            LdapControl::AccountUsableRequest { criticality } => {
+                ("1.3.6.1.4.1.42.2.27.9.5.8", criticality, None)
+            }
+            LdapControl::AccountUsableResponse(usability) => {
+                let inner = match usability {
+                    LdapAccountUsability::Available {
+                        seconds_before_expiration,
+                    } => Tag::Integer(Integer {
+                        class: TagClass::Context,
+                        id: 0,
+                        inner: seconds_before_expiration,
+                    }),
+                    LdapAccountUsability::NotAvailable {
+                        inactive,
+                        reset,
+                        expired,
+                        remaining_grace,
+                        seconds_before_unlock,
+                    } => {
+                        // The booleans default to false, so are only sent
+                        // when true.
+                        let flag = |id, v: bool| {
+                            v.then_some(Tag::Boolean(Boolean {
+                                class: TagClass::Context,
+                                id,
+                                inner: true,
+                            }))
+                        };
+                        let int = |id, v: Option<i64>| {
+                            v.map(|inner| {
+                                Tag::Integer(Integer {
+                                    class: TagClass::Context,
+                                    id,
+                                    inner,
+                                })
+                            })
+                        };
+                        Tag::Sequence(Sequence {
+                            class: TagClass::Context,
+                            id: 1,
+                            inner: vec![
+                                flag(0, inactive),
+                                flag(1, reset),
+                                flag(2, expired),
+                                int(3, remaining_grace),
+                                int(4, seconds_before_unlock),
+                            ]
+                            .into_iter()
+                            .flatten()
+                            .collect(),
+                        })
+                    }
+                };
+                ("1.3.6.1.4.1.42.2.27.9.5.8", false, Some(inner))
+            }
+            LdapControl::Assertion {
+                criticality,
+                filter,
+            } => (
+                "1.3.6.1.1.12",
+                criticality,
+                Some(encode_filter_value(filter)),
+            ),
+            LdapControl::MatchedValues {
+                criticality,
+                filters,
+            } => (
+                "1.2.826.0.1.3344810.2.3",
+                criticality,
+                Some(Tag::Sequence(Sequence {
+                    inner: filters.into_iter().map(encode_filter_value).collect(),
+                    ..Default::default()
+                })),
+            ),
+            LdapControl::AdExtendedDn { criticality, flag } => (
+                "1.2.840.113556.1.4.529",
+                criticality,
+                Some(Tag::Sequence(Sequence {
+                    inner: vec![Tag::Integer(Integer {
+                        inner: flag,
+                        ..Default::default()
+                    })],
+                    ..Default::default()
+                })),
+            ),
+            LdapControl::AdSdFlags { flags } => (
+                "1.2.840.113556.1.4.801",
+                false,
+                Some(Tag::Sequence(Sequence {
+                    inner: vec![Tag::Integer(Integer {
+                        inner: flags as i64,
+                        ..Default::default()
+                    })],
+                    ..Default::default()
+                })),
+            ),
+            LdapControl::GetEffectiveRights {
+                authzid,
+                attributes,
+            } => {
+                let inner: Vec<_> = vec![
+                    Tag::OctetString(OctetString {
+                        inner: Vec::from(authzid),
+                        ..Default::default()
+                    }),
+                    Tag::Sequence(Sequence {
+                        inner: attributes
+                            .into_iter()
+                            .map(|a| {
+                                Tag::OctetString(OctetString {
+                                    inner: Vec::from(a),
+                                    ..Default::default()
+                                })
+                            })
+                            .collect(),
+                        ..Default::default()
+                    }),
+                ];
+
+                (
+                    "1.3.6.1.4.1.42.2.27.9.5.2",
+                    false,
+                    Some(Tag::Sequence(Sequence {
+                        inner,
+                        ..Default::default()
+                    })),
+                )
+            }
+        };
 
-        if let Some(inner_tag) = inner_tag {
+        let value = inner_tag.map(|inner_tag| {
             let mut bytes = BytesMut::new();
             lber_write::encode_into(&mut bytes, inner_tag.into_structure()).unwrap();
-            inner.push(Tag::OctetString(OctetString {
-                inner: bytes.to_vec(),
-                ..Default::default()
-            }));
-        }
+            bytes.to_vec()
+        });
 
-        Tag::Sequence(Sequence {
-            inner,
+        control_tag(oid, crit, value)
+    }
+}
+
+fn control_tag(oid: &str, crit: bool, value: Option<Vec<u8>>) -> Tag {
+    let mut inner = Vec::with_capacity(3);
+
+    inner.push(Tag::OctetString(OctetString {
+        inner: Vec::from(oid),
+        ..Default::default()
+    }));
+    if crit {
+        inner.push(Tag::Boolean(Boolean {
+            inner: true,
             ..Default::default()
-        })
+        }));
+    }
+
+    if let Some(value) = value {
+        inner.push(Tag::OctetString(OctetString {
+            inner: value,
+            ..Default::default()
+        }));
     }
+
+    Tag::Sequence(Sequence {
+        inner,
+        ..Default::default()
+    })
 }
 
 impl TryFrom<StructureTag> for LdapBindCred {
@@ -1303,12 +3523,39 @@ impl TryFrom<StructureTag> for LdapBindCred {
             return Err(());
         }
 
-        match value.id {
-            0 => value
-                .expect_primitive()
-                .and_then(|bv| String::from_utf8(bv).ok())
-                .map(LdapBindCred::Simple)
-                .ok_or(()),
+        match value.id {
+            0 => value
+                .expect_primitive()
+                .and_then(|bv| String::from_utf8(bv).ok())
+                .map(LdapBindCred::Simple)
+                .ok_or(()),
+            3 => {
+                let mut inner = value.expect_constructed().ok_or(())?;
+                inner.reverse();
+
+                let mechanism = inner
+                    .pop()
+                    .and_then(|t| t.match_class(TagClass::Universal))
+                    .and_then(|t| t.match_id(Types::OctetString as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(|bv| String::from_utf8(bv).ok())
+                    .ok_or(())?;
+
+                let credentials = inner
+                    .pop()
+                    .map(|t| {
+                        t.match_class(TagClass::Universal)
+                            .and_then(|t| t.match_id(Types::OctetString as u64))
+                            .and_then(|t| t.expect_primitive())
+                            .ok_or(())
+                    })
+                    .transpose()?;
+
+                Ok(LdapBindCred::Sasl {
+                    mechanism,
+                    credentials,
+                })
+            }
             _ => Err(()),
         }
     }
@@ -1322,17 +3569,16 @@ impl TryFrom<Vec<StructureTag>> for LdapBindRequest {
         // BindRequest
         value.reverse();
 
-        // Check the version is 3
-        let v = value
+        // version INTEGER (1 ..  127)
+        let version = value
             .pop()
             .and_then(|t| t.match_class(TagClass::Universal))
             .and_then(|t| t.match_id(Types::Integer as u64))
             .and_then(|t| t.expect_primitive())
             .and_then(ber_integer_to_i64)
+            .filter(|v| (1..=127).contains(v))
+            .map(|v| v as i32)
             .ok_or(())?;
-        if v != 3 {
-            return Err(());
-        };
 
         // Get the DN
         let dn = value
@@ -1349,7 +3595,17 @@ impl TryFrom<Vec<StructureTag>> for LdapBindRequest {
             .and_then(|v| LdapBindCred::try_from(v).ok())
             .ok_or(())?;
 
-        Ok(LdapBindRequest { dn, cred })
+        Ok(LdapBindRequest { version, dn, cred })
+    }
+}
+
+impl LdapBindRequest {
+    pub fn new_simple_v3(dn: &str, pw: &str) -> Self {
+        LdapBindRequest {
+            version: 3,
+            dn: dn.to_string(),
+            cred: LdapBindCred::Simple(pw.to_string()),
+        }
     }
 }
 
@@ -1357,7 +3613,7 @@ impl From<LdapBindRequest> for Vec<Tag> {
     fn from(value: LdapBindRequest) -> Vec<Tag> {
         vec![
             Tag::Integer(Integer {
-                inner: 3,
+                inner: value.version as i64,
                 ..Default::default()
             }),
             Tag::OctetString(OctetString {
@@ -1369,7 +3625,177 @@ impl From<LdapBindRequest> for Vec<Tag> {
     }
 }
 
+impl LdapOp {
+    pub fn kind(&self) -> LdapOpKind {
+        match self {
+            LdapOp::BindRequest(_) => LdapOpKind::BindRequest,
+            LdapOp::BindResponse(_) => LdapOpKind::BindResponse,
+            LdapOp::UnbindRequest => LdapOpKind::UnbindRequest,
+            LdapOp::SearchRequest(_) => LdapOpKind::SearchRequest,
+            LdapOp::SearchResultEntry(_) => LdapOpKind::SearchResultEntry,
+            LdapOp::SearchResultDone(_) => LdapOpKind::SearchResultDone,
+            LdapOp::ModifyRequest(_) => LdapOpKind::ModifyRequest,
+            LdapOp::ModifyResponse(_) => LdapOpKind::ModifyResponse,
+            LdapOp::AddRequest(_) => LdapOpKind::AddRequest,
+            LdapOp::AddResponse(_) => LdapOpKind::AddResponse,
+            LdapOp::DelRequest(_) => LdapOpKind::DelRequest,
+            LdapOp::DelResponse(_) => LdapOpKind::DelResponse,
+            LdapOp::ModifyDNRequest(_) => LdapOpKind::ModifyDNRequest,
+            LdapOp::ModifyDNResponse(_) => LdapOpKind::ModifyDNResponse,
+            LdapOp::CompareRequest(_) => LdapOpKind::CompareRequest,
+            LdapOp::CompareResponse(_) => LdapOpKind::CompareResponse,
+            LdapOp::AbandonRequest(_) => LdapOpKind::AbandonRequest,
+            LdapOp::ExtendedRequest(_) => LdapOpKind::ExtendedRequest,
+            LdapOp::ExtendedResponse(_) => LdapOpKind::ExtendedResponse,
+            LdapOp::IntermediateResponse(_) => LdapOpKind::IntermediateResponse,
+        }
+    }
+
+    // Sent by a client to a server.
+    pub fn is_request(&self) -> bool {
+        matches!(
+            self,
+            LdapOp::BindRequest(_)
+                | LdapOp::UnbindRequest
+                | LdapOp::SearchRequest(_)
+                | LdapOp::ModifyRequest(_)
+                | LdapOp::AddRequest(_)
+                | LdapOp::DelRequest(_)
+                | LdapOp::ModifyDNRequest(_)
+                | LdapOp::CompareRequest(_)
+                | LdapOp::AbandonRequest(_)
+                | LdapOp::ExtendedRequest(_)
+        )
+    }
+
+    // Sent by a server to a client, including entries and intermediate
+    // responses.
+    pub fn is_response(&self) -> bool {
+        !self.is_request()
+    }
+
+    // A SearchResultDone that refers the client to another server, for when
+    // the base object is not held locally.
+    pub fn search_result_referral(uris: Vec<String>) -> Self {
+        LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::Referral,
+            matcheddn: "".to_string(),
+            message: "".to_string(),
+            referral: uris,
+        })
+    }
+}
+
+// An error from a server handler, carrying what the client is told in the
+// result of the operation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapError {
+    pub code: LdapResultCode,
+    pub matcheddn: String,
+    pub message: String,
+}
+
+impl LdapError {
+    pub fn new(code: LdapResultCode, msg: &str) -> Self {
+        LdapError {
+            code,
+            matcheddn: "".to_string(),
+            message: msg.to_string(),
+        }
+    }
+
+    // The deepest entry of the requested dn that does exist, for errors such
+    // as NoSuchObject.
+    pub fn matched(mut self, dn: &str) -> Self {
+        self.matcheddn = dn.to_string();
+        self
+    }
+}
+
+impl From<LdapResult> for LdapError {
+    fn from(res: LdapResult) -> Self {
+        let LdapResult {
+            code,
+            matcheddn,
+            message,
+            ..
+        } = res;
+        LdapError {
+            code,
+            matcheddn,
+            message,
+        }
+    }
+}
+
+impl From<LdapError> for LdapResult {
+    fn from(e: LdapError) -> Self {
+        let LdapError {
+            code,
+            matcheddn,
+            message,
+        } = e;
+        LdapResult {
+            code,
+            matcheddn,
+            message,
+            referral: Vec::new(),
+        }
+    }
+}
+
 impl LdapResult {
+    // The server requires tls before this operation can proceed.
+    pub fn confidentiality_required(msg: &str) -> Self {
+        LdapResult {
+            code: LdapResultCode::ConfidentialityRequired,
+            matcheddn: "".to_string(),
+            message: msg.to_string(),
+            referral: Vec::new(),
+        }
+    }
+
+    pub fn unwilling_to_perform(msg: &str) -> Self {
+        LdapResult {
+            code: LdapResultCode::UnwillingToPerform,
+            matcheddn: "".to_string(),
+            message: msg.to_string(),
+            referral: Vec::new(),
+        }
+    }
+
+    // If true, the client should issue a StartTLS and then retry.
+    pub fn is_confidentiality_required(&self) -> bool {
+        self.code == LdapResultCode::ConfidentialityRequired
+    }
+
+    // On NoSuchObject this is the deepest entry of the requested dn that
+    // does exist, so a client may retry the search from here. The dn is
+    // returned as is, so any escaping is preserved.
+    pub fn matched_dn(&self) -> Option<&str> {
+        if self.matcheddn.is_empty() {
+            None
+        } else {
+            Some(self.matcheddn.as_str())
+        }
+    }
+
+    // AD puts a more specific error code in the message, such as
+    // "80090308: LdapErr: DSID-0C09044E, comment: AcceptSecurityContext
+    // error, data 52e, v4563" for invalid credentials. For binds this tells
+    // a bad password (52e) from an expired (532) or disabled (533) account.
+    pub fn ad_sub_error(&self) -> Option<u32> {
+        let (_, data) = self.message.split_once(", data ")?;
+        let code = data.split(',').next()?.trim();
+        u32::from_str_radix(code, 16).ok()
+    }
+
+    // A vendor specific code from the message. AD is the only server we
+    // know of that does this.
+    pub fn vendor_code(&self) -> Option<i64> {
+        self.ad_sub_error().map(i64::from)
+    }
+
     fn into_tag_iter(self) -> impl Iterator<Item = Option<Tag>> {
         let LdapResult {
             code,
@@ -1435,7 +3861,16 @@ impl LdapResult {
         let code = value
             .pop()
             .and_then(|t| t.match_class(TagClass::Universal))
-            .and_then(|t| t.match_id(Types::Enumerated as u64))
+            .and_then(|t| {
+                // Some servers will tag this as an integer rather than enum.
+                if t.id == Types::Enumerated as u64
+                    || (!cfg!(feature = "strict") && t.id == Types::Integer as u64)
+                {
+                    Some(t)
+                } else {
+                    None
+                }
+            })
             .and_then(|t| t.expect_primitive())
             .and_then(ber_integer_to_i64)
             .ok_or(())
@@ -1457,10 +3892,26 @@ impl LdapResult {
             .and_then(|bv| String::from_utf8(bv).ok())
             .ok_or(())?;
 
-        let (_referrals, other): (Vec<_>, Vec<_>) = value.into_iter().partition(|v| v.id == 3);
-
-        // assert referrals only is one
-        let referral = Vec::new();
+        let (referrals, other): (Vec<_>, Vec<_>) = value
+            .into_iter()
+            .partition(|v| v.class == TagClass::Context && v.id == 3);
+
+        // There should only be one referral, but if there are more, just
+        // take all of their uris.
+        let referral = referrals
+            .into_iter()
+            .map(|t| t.expect_constructed().ok_or(()))
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .map(|t| {
+                t.match_class(TagClass::Universal)
+                    .and_then(|t| t.match_id(Types::OctetString as u64))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(|bv| String::from_utf8(bv).ok())
+                    .ok_or(())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
 
         Ok((
             LdapResult {
@@ -1498,6 +3949,13 @@ impl LdapBindResponse {
             saslcreds: None,
         }
     }
+
+    // The credentials of a sasl mechanism to return to the client, either
+    // with SaslBindInProgress for another round, or on Success.
+    pub fn with_sasl_creds(mut self, creds: Vec<u8>) -> Self {
+        self.saslcreds = Some(creds);
+        self
+    }
 }
 
 impl TryFrom<Vec<StructureTag>> for LdapBindResponse {
@@ -1505,13 +3963,15 @@ impl TryFrom<Vec<StructureTag>> for LdapBindResponse {
 
     fn try_from(value: Vec<StructureTag>) -> Result<Self, Self::Error> {
         // This MUST be the first thing we do!
-        let (res, _remtag) = LdapResult::try_from_tag(value)?;
+        let (res, remtag) = LdapResult::try_from_tag(value)?;
 
         // Now with the remaining tags, populate anything else we need
-        Ok(LdapBindResponse {
-            res,
-            saslcreds: None,
-        })
+        let saslcreds = remtag
+            .into_iter()
+            .find(|t| t.class == TagClass::Context && t.id == 7)
+            .and_then(|t| t.expect_primitive());
+
+        Ok(LdapBindResponse { res, saslcreds })
     }
 }
 
@@ -1523,8 +3983,9 @@ impl From<LdapBindResponse> for Vec<Tag> {
             .chain(once_with(|| {
                 saslcreds.map(|sc| {
                     Tag::OctetString(OctetString {
-                        inner: Vec::from(sc),
-                        ..Default::default()
+                        id: 7,
+                        class: TagClass::Context,
+                        inner: sc,
                     })
                 })
             }))
@@ -1567,7 +4028,9 @@ impl TryFrom<StructureTag> for LdapFilter {
                 let inner_filt = LdapFilter::try_from(inner)?;
                 Ok(LdapFilter::Not(Box::new(inner_filt)))
             }
-            3 => {
+            // Equality, greaterOrEqual, lessOrEqual and approxMatch share the
+            // same AttributeValueAssertion form.
+            id @ (3 | 5 | 6 | 8) => {
                 let mut inner = value.expect_constructed().ok_or_else(|| {
                     trace!("invalid eq filter");
                 })?;
@@ -1611,7 +4074,12 @@ impl TryFrom<StructureTag> for LdapFilter {
                         trace!("invalid value in eq filter");
                     })?;
 
-                Ok(LdapFilter::Equality(a, v))
+                Ok(match id {
+                    5 => LdapFilter::GreaterOrEqual(a, v),
+                    6 => LdapFilter::LessOrEqual(a, v),
+                    8 => LdapFilter::Approx(a, v),
+                    _ => LdapFilter::Equality(a, v),
+                })
             }
             4 => {
                 let mut inner = value.expect_constructed().ok_or_else(|| {
@@ -1709,20 +4177,9 @@ impl From<LdapFilter> for Tag {
                 class: TagClass::Context,
                 inner: Box::new((*f).into()),
             }),
-            LdapFilter::Equality(a, v) => Tag::Sequence(Sequence {
-                id: 3,
-                class: TagClass::Context,
-                inner: vec![
-                    Tag::OctetString(OctetString {
-                        inner: Vec::from(a),
-                        ..Default::default()
-                    }),
-                    Tag::OctetString(OctetString {
-                        inner: Vec::from(v),
-                        ..Default::default()
-                    }),
-                ],
-            }),
+            LdapFilter::Equality(a, v) => ava_filter_tag(3, a, v),
+            LdapFilter::GreaterOrEqual(a, v) => ava_filter_tag(5, a, v),
+            LdapFilter::LessOrEqual(a, v) => ava_filter_tag(6, a, v),
             LdapFilter::Substring(t, f) => Tag::Sequence(Sequence {
                 id: 4,
                 class: TagClass::Context,
@@ -1766,8 +4223,251 @@ impl From<LdapFilter> for Tag {
                 class: TagClass::Context,
                 inner: Vec::from(a),
             }),
+            LdapFilter::Approx(a, v) => ava_filter_tag(8, a, v),
+        }
+    }
+}
+
+// https://tools.ietf.org/html/rfc4511#section-4.1.8
+fn ava_filter_tag(id: u64, a: String, v: String) -> Tag {
+    Tag::Sequence(Sequence {
+        id,
+        class: TagClass::Context,
+        inner: vec![
+            Tag::OctetString(OctetString {
+                inner: Vec::from(a),
+                ..Default::default()
+            }),
+            Tag::OctetString(OctetString {
+                inner: Vec::from(v),
+                ..Default::default()
+            }),
+        ],
+    })
+}
+
+impl LdapFilter {
+    // Combine with another filter. Nested And filters are flattened.
+    pub fn and(self, other: LdapFilter) -> LdapFilter {
+        match (self, other) {
+            (LdapFilter::And(mut a), LdapFilter::And(b)) => {
+                a.extend(b);
+                LdapFilter::And(a)
+            }
+            (LdapFilter::And(mut a), b) => {
+                a.push(b);
+                LdapFilter::And(a)
+            }
+            (a, LdapFilter::And(b)) => LdapFilter::And(once(a).chain(b).collect()),
+            (a, b) => LdapFilter::And(vec![a, b]),
         }
     }
+
+    // Combine with another filter. Nested Or filters are flattened.
+    pub fn or(self, other: LdapFilter) -> LdapFilter {
+        match (self, other) {
+            (LdapFilter::Or(mut a), LdapFilter::Or(b)) => {
+                a.extend(b);
+                LdapFilter::Or(a)
+            }
+            (LdapFilter::Or(mut a), b) => {
+                a.push(b);
+                LdapFilter::Or(a)
+            }
+            (a, LdapFilter::Or(b)) => LdapFilter::Or(once(a).chain(b).collect()),
+            (a, b) => LdapFilter::Or(vec![a, b]),
+        }
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn not(self) -> LdapFilter {
+        LdapFilter::Not(Box::new(self))
+    }
+
+    // A rough estimate of the cost to match this filter, being the number of
+    // nodes in the filter plus each substring segment. Servers can use this
+    // to reject pathological filters before evaluating them.
+    pub fn complexity(&self) -> usize {
+        match self {
+            LdapFilter::And(inner) | LdapFilter::Or(inner) => inner
+                .iter()
+                .fold(1, |acc, f| acc.saturating_add(f.complexity())),
+            LdapFilter::Not(inner) => inner.complexity().saturating_add(1),
+            LdapFilter::Equality(_, _)
+            | LdapFilter::GreaterOrEqual(_, _)
+            | LdapFilter::LessOrEqual(_, _)
+            | LdapFilter::Approx(_, _)
+            | LdapFilter::Present(_) => 1,
+            LdapFilter::Substring(_, sub) => {
+                1 + sub.initial.iter().count() + sub.any.len() + sub.final_.iter().count()
+            }
+        }
+    }
+
+    // The equality constraints every matching entry must meet, for a backend
+    // to look up in an index before evaluating the full filter. Equalities
+    // under Or or Not are not required, and are ignored.
+    pub fn extract_equalities(&self) -> Vec<(String, String)> {
+        match self {
+            LdapFilter::Equality(a, v) => vec![(a.clone(), v.clone())],
+            LdapFilter::And(inner) => inner.iter().flat_map(|f| f.extract_equalities()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    // A copy with the values asserted against password attributes replaced,
+    // so that it can be logged.
+    pub fn redacted(&self) -> LdapFilter {
+        let mask = |a: &String, v: &String| {
+            if is_password_attribute(a) {
+                REDACTED.to_string()
+            } else {
+                v.clone()
+            }
+        };
+        match self {
+            LdapFilter::And(inner) => LdapFilter::And(inner.iter().map(|f| f.redacted()).collect()),
+            LdapFilter::Or(inner) => LdapFilter::Or(inner.iter().map(|f| f.redacted()).collect()),
+            LdapFilter::Not(inner) => LdapFilter::Not(Box::new(inner.redacted())),
+            LdapFilter::Equality(a, v) => LdapFilter::Equality(a.clone(), mask(a, v)),
+            LdapFilter::GreaterOrEqual(a, v) => LdapFilter::GreaterOrEqual(a.clone(), mask(a, v)),
+            LdapFilter::LessOrEqual(a, v) => LdapFilter::LessOrEqual(a.clone(), mask(a, v)),
+            LdapFilter::Approx(a, v) => LdapFilter::Approx(a.clone(), mask(a, v)),
+            LdapFilter::Substring(a, sub) => LdapFilter::Substring(
+                a.clone(),
+                LdapSubstringFilter {
+                    initial: sub.initial.as_ref().map(|v| mask(a, v)),
+                    any: sub.any.iter().map(|v| mask(a, v)).collect(),
+                    final_: sub.final_.as_ref().map(|v| mask(a, v)),
+                },
+            ),
+            LdapFilter::Present(a) => LdapFilter::Present(a.clone()),
+        }
+    }
+}
+
+// We don't dereference aliases ourselves, as that requires access to the
+// directory. These tell the server what the client asked for.
+// https://www.rfc-editor.org/rfc/rfc4511#section-4.5.1.3
+impl LdapSearchRequest {
+    // Read a single entry, a base search of dn with (objectClass=*).
+    pub fn read_entry(dn: &str, attrs: Vec<String>) -> Self {
+        LdapSearchRequest {
+            base: dn.to_string(),
+            scope: LdapSearchScope::Base,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::Present("objectClass".to_string()),
+            attrs,
+        }
+    }
+
+    // Check that dn exists. 1.1 asks for no attributes, so the entry is
+    // returned with only its dn.
+    pub fn exists(dn: &str) -> Self {
+        LdapSearchRequest {
+            sizelimit: 1,
+            ..LdapSearchRequest::read_entry(dn, vec!["1.1".to_string()])
+        }
+    }
+
+    pub fn dereferences_aliases(&self) -> bool {
+        self.aliases != LdapDerefAliases::Never
+    }
+
+    // Aliases below the base object are dereferenced during the search.
+    pub fn dereferences_in_searching(&self) -> bool {
+        matches!(
+            self.aliases,
+            LdapDerefAliases::InSearching | LdapDerefAliases::Always
+        )
+    }
+
+    // The base object is dereferenced if it is an alias.
+    pub fn dereferences_base_object(&self) -> bool {
+        matches!(
+            self.aliases,
+            LdapDerefAliases::FindingBaseObj | LdapDerefAliases::Always
+        )
+    }
+
+    // Check the requested attributes, allowing * and + for all user and
+    // operational attributes. Without the strict feature this always returns
+    // Ok.
+    pub fn validate(&self) -> Result<(), LdapProtoError> {
+        if !cfg!(feature = "strict") {
+            return Ok(());
+        }
+        self.attrs
+            .iter()
+            .filter(|a| a.as_str() != "*" && a.as_str() != "+")
+            .try_for_each(|a| AttributeDescription::validate(a))
+    }
+
+    pub fn requested_attributes(&self) -> RequestedAttributes {
+        let mut requested = RequestedAttributes {
+            all_user: self.attrs.is_empty(),
+            ..Default::default()
+        };
+        for attr in &self.attrs {
+            match attr.as_str() {
+                "*" => requested.all_user = true,
+                "+" => requested.all_operational = true,
+                // Ignored when listed with other attributes.
+                "1.1" => {}
+                a => {
+                    requested.named.insert(a.to_lowercase());
+                }
+            }
+        }
+        requested
+    }
+
+    // Remove repeated attribute names, ignoring case, keeping the first of
+    // each. The special *, + and 1.1 are kept like any other name.
+    pub fn dedup_attrs(&mut self) {
+        let mut seen = BTreeSet::new();
+        self.attrs.retain(|a| seen.insert(a.to_lowercase()));
+    }
+
+    // One request per requested attribute, each otherwise identical to this
+    // one. Useful to exercise backends that handle attributes independently.
+    pub fn split_by_attr(&self) -> impl Iterator<Item = LdapSearchRequest> + '_ {
+        // Each field is cloned on its own, as cloning self would clone every
+        // attr for each request.
+        self.attrs.iter().map(move |attr| LdapSearchRequest {
+            base: self.base.clone(),
+            scope: self.scope.clone(),
+            aliases: self.aliases.clone(),
+            sizelimit: self.sizelimit,
+            timelimit: self.timelimit,
+            typesonly: self.typesonly,
+            filter: self.filter.clone(),
+            attrs: vec![attr.clone()],
+        })
+    }
+}
+
+// The attributes a search asks to be returned, with the special forms
+// interpreted. https://tools.ietf.org/html/rfc4511#section-4.5.1.8
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestedAttributes {
+    // "*", or no attributes at all, requests every user attribute.
+    pub all_user: bool,
+    // "+" requests every operational attribute.
+    pub all_operational: bool,
+    // Attributes requested by name, lowercased. "1.1" alone requests no
+    // attributes, which is when all of these are empty or false.
+    pub named: BTreeSet<String>,
+}
+
+impl RequestedAttributes {
+    // True when no attributes should be returned, only the entry dn.
+    pub fn is_none(&self) -> bool {
+        !self.all_user && !self.all_operational && self.named.is_empty()
+    }
 }
 
 impl TryFrom<Vec<StructureTag>> for LdapSearchRequest {
@@ -1855,7 +4555,9 @@ impl TryFrom<Vec<StructureTag>> for LdapSearchRequest {
                 if cfg!(feature = "strict") {
                     t.expect_constructed()
                 } else {
-                    Some(Vec::new())
+                    // Tolerate a primitive in place of an empty sequence, but
+                    // never discard attributes that were requested.
+                    Some(t.expect_constructed().unwrap_or_default())
                 }
             })
             .and_then(|vs| {
@@ -1978,12 +4680,23 @@ impl TryFrom<StructureTag> for LdapPartialAttribute {
     type Error = ();
 
     fn try_from(value: StructureTag) -> Result<Self, Self::Error> {
+        LdapPartialAttribute::try_from_tag(value, &DecodeOptions::default()).map_err(|_| ())
+    }
+}
+
+impl LdapPartialAttribute {
+    // Decode an attribute, rejecting it if it has more values than
+    // max_values_per_attribute allows.
+    pub(crate) fn try_from_tag(
+        value: StructureTag,
+        options: &DecodeOptions,
+    ) -> Result<Self, LdapProtoError> {
         // get the inner from the sequence
         let mut inner = value
             .match_class(TagClass::Universal)
             .and_then(|t| t.match_id(Types::Sequence as u64))
             .and_then(|t| t.expect_constructed())
-            .ok_or(())?;
+            .ok_or(LdapProtoError::MessageInvalid)?;
 
         inner.reverse();
 
@@ -1993,25 +4706,33 @@ impl TryFrom<StructureTag> for LdapPartialAttribute {
             .and_then(|t| t.match_id(Types::OctetString as u64))
             .and_then(|t| t.expect_primitive())
             .and_then(|bv| String::from_utf8(bv).ok())
-            .ok_or(())?;
+            .ok_or(LdapProtoError::MessageInvalid)?;
 
-        let vals = inner
+        let bset = inner
             .pop()
             .and_then(|t| t.match_class(TagClass::Universal))
             .and_then(|t| t.match_id(Types::Set as u64))
             .and_then(|t| t.expect_constructed())
-            .and_then(|bset| {
-                let r: Option<Vec<_>> = bset
-                    .into_iter()
-                    .map(|bv| {
-                        bv.match_class(TagClass::Universal)
-                            .and_then(|t| t.match_id(Types::OctetString as u64))
-                            .and_then(|t| t.expect_primitive())
-                    })
-                    .collect();
-                r
+            .ok_or(LdapProtoError::MessageInvalid)?;
+
+        if exceeds(options.max_values_per_attribute, bset.len()) {
+            warn!(len = %bset.len(), "attribute exceeds max_values_per_attribute");
+            return Err(LdapProtoError::DecodeLimitExceeded);
+        }
+
+        // The values are collected into the allocation of bset, which holds
+        // them as tags, so the list costs no allocation of its own. Sizing
+        // a new vec from bset.len() instead measures one more allocation per
+        // attribute in benches/decode.rs.
+        let vals = bset
+            .into_iter()
+            .map(|bv| {
+                bv.match_class(TagClass::Universal)
+                    .and_then(|t| t.match_id(Types::OctetString as u64))
+                    .and_then(|t| t.expect_primitive())
             })
-            .ok_or(())?;
+            .collect::<Option<Vec<_>>>()
+            .ok_or(LdapProtoError::MessageInvalid)?;
 
         Ok(LdapPartialAttribute { atype, vals })
     }
@@ -2020,7 +4741,18 @@ impl TryFrom<StructureTag> for LdapPartialAttribute {
 impl TryFrom<Vec<StructureTag>> for LdapSearchResultEntry {
     type Error = ();
 
-    fn try_from(mut value: Vec<StructureTag>) -> Result<Self, Self::Error> {
+    fn try_from(value: Vec<StructureTag>) -> Result<Self, Self::Error> {
+        LdapSearchResultEntry::try_from_tag(value, &DecodeOptions::default()).map_err(|_| ())
+    }
+}
+
+impl LdapSearchResultEntry {
+    // Decode an entry, rejecting it if it has more attributes, or any
+    // attribute more values, than the options allow.
+    pub(crate) fn try_from_tag(
+        mut value: Vec<StructureTag>,
+        options: &DecodeOptions,
+    ) -> Result<Self, LdapProtoError> {
         value.reverse();
 
         let dn = value
@@ -2029,50 +4761,65 @@ impl TryFrom<Vec<StructureTag>> for LdapSearchResultEntry {
             .and_then(|t| t.match_id(Types::OctetString as u64))
             .and_then(|t| t.expect_primitive())
             .and_then(|bv| String::from_utf8(bv).ok())
-            .ok_or(())?;
+            .ok_or(LdapProtoError::MessageInvalid)?;
 
-        let attributes = value
+        let bset = value
             .pop()
             .and_then(|t| t.match_class(TagClass::Universal))
             .and_then(|t| t.match_id(Types::Sequence as u64))
             .and_then(|t| t.expect_constructed())
-            .and_then(|bset| {
-                let r: Result<Vec<_>, _> = bset
-                    .into_iter()
-                    .map(LdapPartialAttribute::try_from)
-                    .collect();
-                r.ok()
-            })
-            .ok_or(())?;
+            .ok_or(LdapProtoError::MessageInvalid)?;
+
+        if exceeds(options.max_attributes_per_entry, bset.len()) {
+            warn!(len = %bset.len(), "entry exceeds max_attributes_per_entry");
+            return Err(LdapProtoError::DecodeLimitExceeded);
+        }
+
+        let attributes = bset
+            .into_iter()
+            .map(|t| LdapPartialAttribute::try_from_tag(t, options))
+            .collect::<Result<Vec<_>, _>>()?;
 
         Ok(LdapSearchResultEntry { dn, attributes })
     }
 }
 
+// Whether len is over limit, where None is no limit.
+fn exceeds(limit: Option<usize>, len: usize) -> bool {
+    limit.map_or(false, |max| len > max)
+}
+
+fn partial_attribute_tag<I>(atype: String, vals: I) -> Tag
+where
+    I: IntoIterator<Item = Vec<u8>>,
+{
+    Tag::Sequence(Sequence {
+        inner: vec![
+            Tag::OctetString(OctetString {
+                inner: Vec::from(atype),
+                ..Default::default()
+            }),
+            Tag::Set(Set {
+                inner: vals
+                    .into_iter()
+                    .map(|v| {
+                        Tag::OctetString(OctetString {
+                            inner: v,
+                            ..Default::default()
+                        })
+                    })
+                    .collect(),
+                ..Default::default()
+            }),
+        ],
+        ..Default::default()
+    })
+}
+
 impl From<LdapPartialAttribute> for Tag {
     fn from(value: LdapPartialAttribute) -> Tag {
         let LdapPartialAttribute { atype, vals } = value;
-        Tag::Sequence(Sequence {
-            inner: vec![
-                Tag::OctetString(OctetString {
-                    inner: Vec::from(atype),
-                    ..Default::default()
-                }),
-                Tag::Set(Set {
-                    inner: vals
-                        .into_iter()
-                        .map(|v| {
-                            Tag::OctetString(OctetString {
-                                inner: Vec::from(v),
-                                ..Default::default()
-                            })
-                        })
-                        .collect(),
-                    ..Default::default()
-                }),
-            ],
-            ..Default::default()
-        })
+        partial_attribute_tag(atype, vals)
     }
 }
 
@@ -2092,6 +4839,59 @@ impl From<LdapSearchResultEntry> for Vec<Tag> {
     }
 }
 
+// Build and encode a search result entry directly from iterators of values,
+// rather than materialising an LdapSearchResultEntry first. This is useful
+// for servers streaming entries with many values.
+pub struct LdapSearchResultEntryBuilder {
+    dn: String,
+    attributes: Vec<Tag>,
+}
+
+impl LdapSearchResultEntryBuilder {
+    pub fn new(dn: String) -> Self {
+        LdapSearchResultEntryBuilder {
+            dn,
+            attributes: Vec::new(),
+        }
+    }
+
+    pub fn add_attribute<I>(mut self, atype: String, vals: I) -> Self
+    where
+        I: IntoIterator<Item = Vec<u8>>,
+    {
+        self.attributes.push(partial_attribute_tag(atype, vals));
+        self
+    }
+
+    pub fn encode_into(self, msgid: i32, buf: &mut BytesMut) -> std::io::Result<()> {
+        let LdapSearchResultEntryBuilder { dn, attributes } = self;
+        let tag = Tag::Sequence(Sequence {
+            inner: vec![
+                Tag::Integer(Integer {
+                    inner: msgid as i64,
+                    ..Default::default()
+                }),
+                Tag::Sequence(Sequence {
+                    class: TagClass::Application,
+                    id: 4,
+                    inner: vec![
+                        Tag::OctetString(OctetString {
+                            inner: Vec::from(dn),
+                            ..Default::default()
+                        }),
+                        Tag::Sequence(Sequence {
+                            inner: attributes,
+                            ..Default::default()
+                        }),
+                    ],
+                }),
+            ],
+            ..Default::default()
+        });
+        lber_write::encode_into(buf, tag.into_structure())
+    }
+}
+
 impl TryFrom<Vec<StructureTag>> for LdapExtendedRequest {
     type Error = ();
 
@@ -2224,6 +5024,22 @@ impl LdapExtendedResponse {
             value: None,
         }
     }
+
+    // The response to an extended request with an oid we don't implement.
+    // This is a protocolError, and carries no responseName or value.
+    // https://tools.ietf.org/html/rfc4511#section-4.12
+    pub fn unsupported() -> Self {
+        LdapExtendedResponse {
+            res: LdapResult {
+                code: LdapResultCode::ProtocolError,
+                matcheddn: "".to_string(),
+                message: "unsupported extended operation".to_string(),
+                referral: Vec::new(),
+            },
+            name: None,
+            value: None,
+        }
+    }
 }
 
 impl TryFrom<Vec<StructureTag>> for LdapIntermediateResponse {
@@ -2634,6 +5450,68 @@ impl TryFrom<Vec<StructureTag>> for LdapAddRequest {
     }
 }
 
+impl LdapCompareRequest {
+    // The compare equivalent to an equality filter, for backends that
+    // implement compare with their filter engine. Any other filter has no
+    // equivalent.
+    pub fn from_equality(dn: &str, filter: &LdapFilter) -> Option<Self> {
+        match filter {
+            LdapFilter::Equality(atype, value) => Some(LdapCompareRequest {
+                dn: dn.to_string(),
+                atype: atype.clone(),
+                assertion_value: value.as_bytes().to_vec(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<Vec<StructureTag>> for LdapCompareRequest {
+    type Error = ();
+
+    fn try_from(mut value: Vec<StructureTag>) -> Result<Self, Self::Error> {
+        value.reverse();
+
+        let dn = value
+            .pop()
+            .and_then(|t| t.match_class(TagClass::Universal))
+            .and_then(|t| t.match_id(Types::OctetString as u64))
+            .and_then(|t| t.expect_primitive())
+            .and_then(|bv| String::from_utf8(bv).ok())
+            .ok_or(())?;
+
+        let mut ava = value
+            .pop()
+            .and_then(|t| t.match_class(TagClass::Universal))
+            .and_then(|t| t.match_id(Types::Sequence as u64))
+            .and_then(|t| t.expect_constructed())
+            .ok_or(())?;
+        ava.reverse();
+
+        let atype = ava
+            .pop()
+            .and_then(|t| t.match_class(TagClass::Universal))
+            .and_then(|t| t.match_id(Types::OctetString as u64))
+            .and_then(|t| t.expect_primitive())
+            .and_then(|bv| String::from_utf8(bv).ok())
+            .ok_or(())?;
+
+        // Kept as is, since it need not be utf8.
+        let assertion_value = ava
+            .pop()
+            .and_then(|t| t.match_class(TagClass::Universal))
+            .and_then(|t| t.match_id(Types::OctetString as u64))
+            .and_then(|t| t.expect_primitive())
+            .ok_or(())?;
+
+        Ok(LdapCompareRequest {
+            dn,
+            atype,
+            assertion_value,
+        })
+    }
+}
+
 impl From<LdapModify> for Tag {
     fn from(value: LdapModify) -> Tag {
         let LdapModify {
@@ -2671,6 +5549,48 @@ impl From<LdapModifyRequest> for Vec<Tag> {
     }
 }
 
+impl LdapModifyRequest {
+    // We accept an empty list of changes when decoding, so that a server can
+    // respond to it, rather than dropping the connection.
+    pub fn validate(&self) -> Result<(), LdapProtoError> {
+        if self.changes.is_empty() {
+            return Err(LdapProtoError::ModifyChangesEmpty);
+        }
+        self.changes
+            .iter()
+            .try_for_each(|c| c.modification.validate())
+    }
+
+    // Summarise each change for audit logs. Values are never included, and
+    // sensitive attributes are explicitly marked as redacted.
+    pub fn describe(&self) -> Vec<String> {
+        self.changes
+            .iter()
+            .map(
+                |LdapModify {
+                     operation,
+                     modification,
+                 }| {
+                    let op = match operation {
+                        LdapModifyType::Add => "ADD",
+                        LdapModifyType::Delete => "DELETE",
+                        LdapModifyType::Replace => "REPLACE",
+                    };
+                    let count = match modification.vals.len() {
+                        1 => "1 value".to_string(),
+                        n => format!("{} values", n),
+                    };
+                    if is_password_attribute(&modification.atype) {
+                        format!("{} {} ({}, redacted)", op, modification.atype, count)
+                    } else {
+                        format!("{} {} ({})", op, modification.atype, count)
+                    }
+                },
+            )
+            .collect()
+    }
+}
+
 impl From<LdapAddRequest> for Vec<Tag> {
     fn from(value: LdapAddRequest) -> Vec<Tag> {
         let LdapAddRequest { dn, attributes } = value;
@@ -2687,6 +5607,125 @@ impl From<LdapAddRequest> for Vec<Tag> {
     }
 }
 
+impl TryFrom<Vec<StructureTag>> for LdapModifyDNRequest {
+    type Error = ();
+
+    fn try_from(mut value: Vec<StructureTag>) -> Result<Self, Self::Error> {
+        value.reverse();
+
+        let dn = value
+            .pop()
+            .and_then(|t| t.match_class(TagClass::Universal))
+            .and_then(|t| t.match_id(Types::OctetString as u64))
+            .and_then(|t| t.expect_primitive())
+            .and_then(|bv| String::from_utf8(bv).ok())
+            .ok_or(())?;
+
+        let newrdn = value
+            .pop()
+            .and_then(|t| t.match_class(TagClass::Universal))
+            .and_then(|t| t.match_id(Types::OctetString as u64))
+            .and_then(|t| t.expect_primitive())
+            .and_then(|bv| String::from_utf8(bv).ok())
+            .ok_or(())?;
+
+        let deleteoldrdn = value
+            .pop()
+            .and_then(|t| t.match_class(TagClass::Universal))
+            .and_then(|t| t.match_id(Types::Boolean as u64))
+            .and_then(|t| t.expect_primitive())
+            .and_then(ber_bool_to_bool)
+            .ok_or(())?;
+
+        let new_superior = value
+            .pop()
+            .map(|t| {
+                t.match_class(TagClass::Context)
+                    .and_then(|t| t.match_id(0))
+                    .and_then(|t| t.expect_primitive())
+                    .and_then(|bv| String::from_utf8(bv).ok())
+                    .ok_or(())
+            })
+            .transpose()?;
+
+        Ok(LdapModifyDNRequest {
+            dn,
+            newrdn,
+            deleteoldrdn,
+            new_superior,
+        })
+    }
+}
+
+impl From<LdapModifyDNRequest> for Vec<Tag> {
+    fn from(value: LdapModifyDNRequest) -> Vec<Tag> {
+        let LdapModifyDNRequest {
+            dn,
+            newrdn,
+            deleteoldrdn,
+            new_superior,
+        } = value;
+        once_with(|| {
+            Some(Tag::OctetString(OctetString {
+                inner: Vec::from(dn),
+                ..Default::default()
+            }))
+        })
+        .chain(once_with(|| {
+            Some(Tag::OctetString(OctetString {
+                inner: Vec::from(newrdn),
+                ..Default::default()
+            }))
+        }))
+        .chain(once_with(|| {
+            Some(Tag::Boolean(Boolean {
+                inner: deleteoldrdn,
+                ..Default::default()
+            }))
+        }))
+        .chain(once_with(|| {
+            new_superior.map(|sup| {
+                Tag::OctetString(OctetString {
+                    class: TagClass::Context,
+                    id: 0,
+                    inner: Vec::from(sup),
+                })
+            })
+        }))
+        .flatten()
+        .collect()
+    }
+}
+
+impl From<LdapCompareRequest> for Vec<Tag> {
+    fn from(value: LdapCompareRequest) -> Vec<Tag> {
+        let LdapCompareRequest {
+            dn,
+            atype,
+            assertion_value,
+        } = value;
+        vec![
+            Tag::OctetString(OctetString {
+                inner: Vec::from(dn),
+                ..Default::default()
+            }),
+            Tag::Sequence(Sequence {
+                inner: vec![
+                    Tag::OctetString(OctetString {
+                        inner: Vec::from(atype),
+                        ..Default::default()
+                    }),
+                    Tag::OctetString(OctetString {
+                        inner: assertion_value,
+                        ..Default::default()
+                    }),
+                ],
+                ..Default::default()
+            }),
+        ]
+    }
+}
+
 impl TryFrom<i64> for LdapResultCode {
     type Error = ();
 
@@ -2740,10 +5779,136 @@ impl TryFrom<i64> for LdapResultCode {
     }
 }
 
+// Descriptions of vendor specific codes, which are found in the message of a
+// result, to improve diagnostics. The result code itself is unchanged.
+#[derive(Debug, Clone, Default)]
+pub struct ResultCodeRegistry {
+    codes: BTreeMap<i64, String>,
+}
+
+impl ResultCodeRegistry {
+    pub fn register(mut self, code: i64, description: &str) -> Self {
+        self.codes.insert(code, description.to_string());
+        self
+    }
+
+    // The registered description of the vendor code if there is one, else
+    // the description of the result code.
+    pub fn description<'a>(&'a self, res: &LdapResult) -> &'a str {
+        res.vendor_code()
+            .and_then(|c| self.codes.get(&c))
+            .map(|d| d.as_str())
+            .unwrap_or_else(|| res.code.description())
+    }
+}
+
+impl LdapResultCode {
+    pub fn description(&self) -> &'static str {
+        match self {
+            LdapResultCode::Success => "success",
+            LdapResultCode::OperationsError => "operations error",
+            LdapResultCode::ProtocolError => "protocol error",
+            LdapResultCode::TimeLimitExceeded => "time limit exceeded",
+            LdapResultCode::SizeLimitExceeded => "size limit exceeded",
+            LdapResultCode::CompareFalse => "compare false",
+            LdapResultCode::CompareTrue => "compare true",
+            LdapResultCode::AuthMethodNotSupported => "auth method not supported",
+            LdapResultCode::StrongerAuthRequired => "stronger auth required",
+            LdapResultCode::Referral => "referral",
+            LdapResultCode::AdminLimitExceeded => "admin limit exceeded",
+            LdapResultCode::UnavailableCriticalExtension => "unavailable critical extension",
+            LdapResultCode::ConfidentialityRequired => "confidentiality required",
+            LdapResultCode::SaslBindInProgress => "sasl bind in progress",
+            LdapResultCode::NoSuchAttribute => "no such attribute",
+            LdapResultCode::UndefinedAttributeType => "undefined attribute type",
+            LdapResultCode::InappropriateMatching => "inappropriate matching",
+            LdapResultCode::ConstraintViolation => "constraint violation",
+            LdapResultCode::AttributeOrValueExists => "attribute or value exists",
+            LdapResultCode::InvalidAttributeSyntax => "invalid attribute syntax",
+            LdapResultCode::NoSuchObject => "no such object",
+            LdapResultCode::AliasProblem => "alias problem",
+            LdapResultCode::InvalidDNSyntax => "invalid dn syntax",
+            LdapResultCode::AliasDereferencingProblem => "alias dereferencing problem",
+            LdapResultCode::InappropriateAuthentication => "inappropriate authentication",
+            LdapResultCode::InvalidCredentials => "invalid credentials",
+            LdapResultCode::InsufficentAccessRights => "insufficient access rights",
+            LdapResultCode::Busy => "busy",
+            LdapResultCode::Unavailable => "unavailable",
+            LdapResultCode::UnwillingToPerform => "unwilling to perform",
+            LdapResultCode::LoopDetect => "loop detected",
+            LdapResultCode::NamingViolation => "naming violation",
+            LdapResultCode::ObjectClassViolation => "object class violation",
+            LdapResultCode::NotAllowedOnNonLeaf => "not allowed on non leaf",
+            LdapResultCode::NotALlowedOnRDN => "not allowed on rdn",
+            LdapResultCode::EntryAlreadyExists => "entry already exists",
+            LdapResultCode::ObjectClassModsProhibited => "object class mods prohibited",
+            LdapResultCode::AffectsMultipleDSAs => "affects multiple dsas",
+            LdapResultCode::Other => "other",
+            LdapResultCode::EsyncRefreshRequired => "e-sync refresh required",
+        }
+    }
+
+    // A rough http status for this result, for gateways that expose ldap
+    // over a rest api.
+    pub fn http_status(&self) -> u16 {
+        match self {
+            LdapResultCode::Success
+            | LdapResultCode::CompareFalse
+            | LdapResultCode::CompareTrue => 200,
+            LdapResultCode::Referral => 307,
+            LdapResultCode::ProtocolError
+            | LdapResultCode::UndefinedAttributeType
+            | LdapResultCode::InappropriateMatching
+            | LdapResultCode::InvalidAttributeSyntax
+            | LdapResultCode::InvalidDNSyntax
+            | LdapResultCode::UnavailableCriticalExtension => 400,
+            LdapResultCode::AuthMethodNotSupported
+            | LdapResultCode::StrongerAuthRequired
+            | LdapResultCode::ConfidentialityRequired
+            | LdapResultCode::SaslBindInProgress
+            | LdapResultCode::InappropriateAuthentication
+            | LdapResultCode::InvalidCredentials => 401,
+            // Unwilling to perform is a refusal by server policy, rather than
+            // a method the server lacks.
+            LdapResultCode::InsufficentAccessRights | LdapResultCode::UnwillingToPerform => 403,
+            LdapResultCode::NoSuchObject | LdapResultCode::NoSuchAttribute => 404,
+            LdapResultCode::AttributeOrValueExists
+            | LdapResultCode::EntryAlreadyExists
+            | LdapResultCode::NotAllowedOnNonLeaf => 409,
+            LdapResultCode::EsyncRefreshRequired => 410,
+            LdapResultCode::SizeLimitExceeded | LdapResultCode::AdminLimitExceeded => 413,
+            LdapResultCode::ConstraintViolation
+            | LdapResultCode::NamingViolation
+            | LdapResultCode::ObjectClassViolation
+            | LdapResultCode::NotALlowedOnRDN
+            | LdapResultCode::ObjectClassModsProhibited
+            | LdapResultCode::AliasProblem
+            | LdapResultCode::AliasDereferencingProblem => 422,
+            LdapResultCode::Busy => 429,
+            LdapResultCode::Unavailable => 503,
+            LdapResultCode::TimeLimitExceeded => 504,
+            LdapResultCode::LoopDetect => 508,
+            LdapResultCode::OperationsError
+            | LdapResultCode::AffectsMultipleDSAs
+            | LdapResultCode::Other => 500,
+        }
+    }
+}
+
 // Implement by hand to avoid printing the password.
 impl std::fmt::Debug for LdapBindCred {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, r#"Simple("********")"#)
+        match self {
+            LdapBindCred::Simple(_) => f.debug_tuple("Simple").field(&REDACTED).finish(),
+            LdapBindCred::Sasl {
+                mechanism,
+                credentials,
+            } => f
+                .debug_struct("Sasl")
+                .field("mechanism", mechanism)
+                .field("credentials", &credentials.as_ref().map(|_| REDACTED))
+                .finish(),
+        }
     }
 }
 
@@ -2752,8 +5917,8 @@ impl std::fmt::Debug for LdapPartialAttribute {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut f = f.debug_struct("LdapPartialAttribute");
         f.field("atype", &self.atype);
-        if self.atype == "userPassword" && self.vals.len() == 1 {
-            f.field("vals", &vec!["********".to_string()]);
+        if is_password_attribute(&self.atype) {
+            f.field("vals", &vec![REDACTED; self.vals.len()]);
         } else {
             f.field("vals", &self.vals);
         }
@@ -2771,6 +5936,31 @@ impl std::fmt::Debug for LdapExtendedRequest {
     }
 }
 
+// Implement by hand to avoid printing the password. The response to a
+// password modify has no name, so the value can't be selectively redacted.
+impl std::fmt::Debug for LdapExtendedResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut f = f.debug_struct("LdapExtendedResponse");
+        f.field("res", &self.res);
+        f.field("name", &self.name);
+        f.field("value", &self.value.as_ref().map(|_| "vec![...]"));
+        f.finish()
+    }
+}
+
+// Implement by hand to avoid printing the password.
+impl std::fmt::Debug for LdapPasswordModifyResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut f = f.debug_struct("LdapPasswordModifyResponse");
+        f.field("res", &self.res);
+        f.field(
+            "gen_password",
+            &self.gen_password.as_ref().map(|_| REDACTED),
+        );
+        f.finish()
+    }
+}
+
 // Implement by hand to avoid printing the password.
 impl std::fmt::Debug for LdapPasswordModifyRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -2778,16 +5968,260 @@ impl std::fmt::Debug for LdapPasswordModifyRequest {
         f.field("user_identity", &self.user_identity);
         f.field(
             "old_password",
-            &self.old_password.as_ref().map(|_| "********"),
+            &self.old_password.as_ref().map(|_| REDACTED),
         );
         f.field(
             "new_password",
-            &self.old_password.as_ref().map(|_| "********"),
+            &self.new_password.as_ref().map(|_| REDACTED),
         );
         f.finish()
     }
 }
 
+// A compact single line summary, for access logs. Credentials and attribute
+// values are never shown. Search filters are, with the values asserted
+// against password attributes masked.
+impl std::fmt::Display for LdapMsg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} {:?}", self.msgid, self.op.kind())?;
+        match &self.op {
+            LdapOp::BindRequest(lbr) => {
+                write!(f, " dn={:?}", lbr.dn)?;
+                match &lbr.cred {
+                    LdapBindCred::Simple(_) => write!(f, " simple")?,
+                    LdapBindCred::Sasl { mechanism, .. } => write!(f, " sasl={}", mechanism)?,
+                }
+            }
+            LdapOp::BindResponse(LdapBindResponse { res, .. })
+            | LdapOp::ExtendedResponse(LdapExtendedResponse { res, .. })
+            | LdapOp::SearchResultDone(res)
+            | LdapOp::ModifyResponse(res)
+            | LdapOp::AddResponse(res)
+            | LdapOp::DelResponse(res)
+            | LdapOp::ModifyDNResponse(res)
+            | LdapOp::CompareResponse(res) => {
+                write!(f, " code={:?}", res.code)?;
+                if !res.message.is_empty() {
+                    write!(f, " message={:?}", res.message)?;
+                }
+            }
+            LdapOp::SearchRequest(lsr) => {
+                let scope = match lsr.scope {
+                    LdapSearchScope::Base => "base",
+                    LdapSearchScope::OneLevel => "one",
+                    LdapSearchScope::Subtree => "sub",
+                };
+                write!(
+                    f,
+                    " base={:?} scope={} filter={}",
+                    lsr.base,
+                    scope,
+                    lsr.filter.redacted()
+                )?;
+                if !lsr.attrs.is_empty() {
+                    write!(f, " attrs={}", lsr.attrs.join(","))?;
+                }
+            }
+            LdapOp::SearchResultEntry(LdapSearchResultEntry { dn, .. })
+            | LdapOp::ModifyRequest(LdapModifyRequest { dn, .. })
+            | LdapOp::AddRequest(LdapAddRequest { dn, .. })
+            | LdapOp::DelRequest(dn) => write!(f, " dn={:?}", dn)?,
+            LdapOp::ModifyDNRequest(lmdr) => {
+                write!(f, " dn={:?} newrdn={:?}", lmdr.dn, lmdr.newrdn)?;
+                if let Some(sup) = &lmdr.new_superior {
+                    write!(f, " newsuperior={:?}", sup)?;
+                }
+            }
+            LdapOp::CompareRequest(lcr) => write!(f, " dn={:?} attr={}", lcr.dn, lcr.atype)?,
+            LdapOp::AbandonRequest(id) => write!(f, " abandon=#{}", id)?,
+            LdapOp::ExtendedRequest(ler) => write!(f, " name={}", ler.name)?,
+            LdapOp::UnbindRequest | LdapOp::IntermediateResponse(_) => {}
+        }
+        if !self.ctrl.is_empty() {
+            let oids: Vec<_> = self.ctrl.iter().map(|c| c.oid()).collect();
+            write!(f, " ctrl={}", oids.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+// The string form of a filter. https://www.rfc-editor.org/rfc/rfc4515
+impl std::fmt::Display for LdapFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn escape(f: &mut std::fmt::Formatter<'_>, v: &str) -> std::fmt::Result {
+            for c in v.chars() {
+                match c {
+                    '*' => write!(f, "\\2a")?,
+                    '(' => write!(f, "\\28")?,
+                    ')' => write!(f, "\\29")?,
+                    '\\' => write!(f, "\\5c")?,
+                    '\0' => write!(f, "\\00")?,
+                    c => write!(f, "{}", c)?,
+                }
+            }
+            Ok(())
+        }
+
+        match self {
+            LdapFilter::And(inner) => {
+                write!(f, "(&")?;
+                for i in inner {
+                    write!(f, "{}", i)?;
+                }
+                write!(f, ")")
+            }
+            LdapFilter::Or(inner) => {
+                write!(f, "(|")?;
+                for i in inner {
+                    write!(f, "{}", i)?;
+                }
+                write!(f, ")")
+            }
+            LdapFilter::Not(inner) => write!(f, "(!{})", inner),
+            LdapFilter::Equality(a, v) => {
+                write!(f, "({}=", a)?;
+                escape(f, v)?;
+                write!(f, ")")
+            }
+            LdapFilter::GreaterOrEqual(a, v) => {
+                write!(f, "({}>=", a)?;
+                escape(f, v)?;
+                write!(f, ")")
+            }
+            LdapFilter::LessOrEqual(a, v) => {
+                write!(f, "({}<=", a)?;
+                escape(f, v)?;
+                write!(f, ")")
+            }
+            LdapFilter::Substring(a, sub) => {
+                write!(f, "({}=", a)?;
+                if let Some(i) = &sub.initial {
+                    escape(f, i)?;
+                }
+                write!(f, "*")?;
+                for any in &sub.any {
+                    escape(f, any)?;
+                    write!(f, "*")?;
+                }
+                if let Some(fin) = &sub.final_ {
+                    escape(f, fin)?;
+                }
+                write!(f, ")")
+            }
+            LdapFilter::Present(a) => write!(f, "({}=*)", a),
+            LdapFilter::Approx(a, v) => {
+                write!(f, "({}~=", a)?;
+                escape(f, v)?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+// The control value is an OCTET STRING containing the BER of the inner
+// value. Some servers don't wrap it, and send the inner SEQUENCE as is.
+//
+// The elements of the inner SEQUENCE are returned in reverse so that the
+// fields can be popped in order.
+pub(crate) fn parse_control_value(
+    value_tag: Option<StructureTag>,
+) -> Result<Vec<StructureTag>, LdapProtoError> {
+    let StructureTag { class, id, payload } =
+        value_tag.ok_or(LdapProtoError::ControlValueMissing)?;
+    if class != TagClass::Universal {
+        return Err(LdapProtoError::ControlBerInvalid);
+    }
+
+    let mut value = match payload {
+        PL::C(_) if id == Types::OctetString as u64 => {
+            let value_ber = octet_string_bytes(StructureTag { class, id, payload })
+                .ok_or(LdapProtoError::ControlBerInvalid)?;
+            return parse_control_value(Some(StructureTag {
+                class,
+                id,
+                payload: PL::P(value_ber),
+            }));
+        }
+        PL::P(value_ber) if id == Types::OctetString as u64 => {
+            let mut parser = Parser::new();
+            match *parser.handle(Input::Element(&value_ber)) {
+                ConsumerState::Done(_size, ref msg) => msg
+                    .clone()
+                    .expect_constructed()
+                    .ok_or(LdapProtoError::ControlBerInvalid)?,
+                _ => return Err(LdapProtoError::ControlBerInvalid),
+            }
+        }
+        PL::C(inner) if id == Types::Sequence as u64 && !cfg!(feature = "strict") => {
+            trace!("control value is not wrapped in an octet string");
+            inner
+        }
+        _ => return Err(LdapProtoError::ControlBerInvalid),
+    };
+
+    value.reverse();
+    Ok(value)
+}
+
+// For controls where the value is a single element of any kind, such as a
+// filter, rather than a SEQUENCE of fields.
+pub(crate) fn parse_control_value_tag(
+    value_tag: Option<StructureTag>,
+) -> Result<StructureTag, LdapProtoError> {
+    let value_ber = value_tag
+        .ok_or(LdapProtoError::ControlValueMissing)
+        .map(octet_string_bytes)?
+        .ok_or(LdapProtoError::ControlBerInvalid)?;
+
+    let mut parser = Parser::new();
+    match *parser.handle(Input::Element(&value_ber)) {
+        ConsumerState::Done(_size, ref msg) => Ok(msg.clone()),
+        _ => Err(LdapProtoError::ControlBerInvalid),
+    }
+}
+
+// The content of an OCTET STRING. ldap requires the primitive form, but BER
+// also allows a constructed form split into segments, which some encoders
+// emit for long values. That is only accepted without the strict feature.
+// https://tools.ietf.org/html/rfc4511#section-5.1
+fn octet_string_bytes(tag: StructureTag) -> Option<Vec<u8>> {
+    let tag = tag
+        .match_class(TagClass::Universal)
+        .and_then(|t| t.match_id(Types::OctetString as u64))?;
+    match tag.payload {
+        PL::P(v) => Some(v),
+        PL::C(segments) if !cfg!(feature = "strict") => {
+            trace!("octet string is constructed");
+            segments
+                .into_iter()
+                .map(octet_string_bytes)
+                .collect::<Option<Vec<_>>>()
+                .map(|s| s.concat())
+        }
+        PL::C(_) => None,
+    }
+}
+
+// For controls where the value is a single primitive, such as a BOOLEAN or
+// INTEGER, rather than a SEQUENCE.
+pub(crate) fn parse_control_value_primitive(
+    value_tag: Option<StructureTag>,
+) -> Result<StructureTag, LdapProtoError> {
+    parse_control_value_tag(value_tag).and_then(|t| match t.payload {
+        PL::P(_) => Ok(t),
+        PL::C(_) => Err(LdapProtoError::ControlBerInvalid),
+    })
+}
+
+// Filters in a control value are encoded as they are in a search request.
+pub(crate) fn encode_filter_value(filter: LdapFilter) -> Tag {
+    filter.into()
+}
+
+pub(crate) fn decode_filter_value(tag: StructureTag) -> Result<LdapFilter, LdapProtoError> {
+    LdapFilter::try_from(tag).map_err(|_| LdapProtoError::ControlValueInvalid)
+}
+
 fn ber_bool_to_bool(bv: Vec<u8>) -> Option<bool> {
     bv.get(0).map(|v| !matches!(v, 0))
 }
@@ -2804,3 +6238,16 @@ fn ber_integer_to_i64(bv: Vec<u8>) -> Option<i64> {
     raw[base..(bv.len() + base)].clone_from_slice(&bv[..]);
     Some(i64::from_be_bytes(raw))
 }
+
+// As ber_integer_to_i64, but sign extended, so that a 4 byte INTEGER with the
+// high bit set is negative. Values that don't fit in an i32 are None.
+fn ber_integer_to_i32(bv: &[u8]) -> Option<i32> {
+    let fill = match bv.first()? {
+        b if b & 0x80 != 0 => 0xff,
+        _ => 0,
+    };
+    let mut raw = [fill; 4];
+    let base = 4usize.checked_sub(bv.len())?;
+    raw[base..].clone_from_slice(bv);
+    Some(i32::from_be_bytes(raw))
+}
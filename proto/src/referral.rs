@@ -0,0 +1,133 @@
+// The LDAP urls of a referral or continuation reference, split into the
+// parts a client needs to follow them.
+// https://tools.ietf.org/html/rfc4511#section-4.1.10
+// https://tools.ietf.org/html/rfc4516#section-2
+
+use crate::proto::{LdapResult, LdapSearchScope};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContinuationReference {
+    // ldap, ldaps or ldapi.
+    pub scheme: String,
+    // None when the url gives no host, meaning the client's default.
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub dn: String,
+    pub attrs: Vec<String>,
+    // None means the scope of the original search.
+    pub scope: Option<LdapSearchScope>,
+    pub filter: Option<String>,
+}
+
+impl ContinuationReference {
+    pub fn parse(url: &str) -> Option<Self> {
+        let (scheme, rest) = url.split_once("://")?;
+        let scheme = scheme.to_ascii_lowercase();
+        if !matches!(scheme.as_str(), "ldap" | "ldaps" | "ldapi") {
+            return None;
+        }
+
+        let (hostport, rest) = rest.split_once('/').unwrap_or((rest, ""));
+        let (host, port) = split_host_port(hostport)?;
+
+        // dn ? attributes ? scope ? filter ? extensions
+        let mut parts = rest.split('?');
+        let dn = percent_decode(parts.next().unwrap_or_default())?;
+        let attrs = match parts.next().unwrap_or_default() {
+            "" => Vec::new(),
+            a => a
+                .split(',')
+                .map(percent_decode)
+                .collect::<Option<Vec<_>>>()?,
+        };
+        let scope = match parts
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "" => None,
+            "base" => Some(LdapSearchScope::Base),
+            "one" => Some(LdapSearchScope::OneLevel),
+            "sub" => Some(LdapSearchScope::Subtree),
+            _ => return None,
+        };
+        let filter = match parts.next().unwrap_or_default() {
+            "" => None,
+            f => Some(percent_decode(f)?),
+        };
+        // Extensions are not interpreted. Any more parts are invalid.
+        let _extensions = parts.next();
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(ContinuationReference {
+            scheme,
+            host,
+            port,
+            dn,
+            attrs,
+            scope,
+            filter,
+        })
+    }
+}
+
+impl LdapResult {
+    // The referral urls of this result. Urls that can't be parsed are
+    // skipped.
+    pub fn continuation_references(&self) -> Vec<ContinuationReference> {
+        self.referral
+            .iter()
+            .filter_map(|url| {
+                let r = ContinuationReference::parse(url);
+                if r.is_none() {
+                    warn!(%url, "skipping invalid referral url");
+                }
+                r
+            })
+            .collect()
+    }
+}
+
+fn split_host_port(s: &str) -> Option<(Option<String>, Option<u16>)> {
+    // An ipv6 address is in brackets, as it contains colons.
+    let (host, port) = match s.strip_prefix('[') {
+        Some(v6) => {
+            let (host, rest) = v6.split_once(']')?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':')?)),
+            }
+        }
+        None => match s.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (s, None),
+        },
+    };
+    let port = match port {
+        Some(p) => Some(p.parse().ok()?),
+        None => None,
+    };
+    let host = match host {
+        "" => None,
+        h => Some(percent_decode(h)?),
+    };
+    Some((host, port))
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -0,0 +1,122 @@
+// A sasl bind followed by a whoami, to learn the identity the server
+// resolved from the mechanism. This holds no io: send each message it gives,
+// and pass each response to step. The caller chooses each msgid, so they
+// don't collide with other operations on the connection.
+// https://tools.ietf.org/html/rfc4513#section-5.2.2
+// https://tools.ietf.org/html/rfc4532
+
+use crate::proto::*;
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SaslWhoamiState {
+    Binding,
+    Bound,
+    Whoami,
+    Done,
+}
+
+#[derive(Debug, Clone)]
+pub struct LdapSaslWhoami {
+    mechanism: String,
+    msgid: i32,
+    state: SaslWhoamiState,
+}
+
+// The identity the server resolved, and the mechanism that bound it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapSaslIdentity {
+    pub mechanism: String,
+    // As returned by whoami, such as dn:cn=demo,dc=example,dc=com.
+    pub authzid: Option<String>,
+}
+
+impl LdapSaslIdentity {
+    pub fn parsed(&self) -> Option<LdapAuthzId> {
+        LdapWhoamiResponse {
+            dn: self.authzid.clone(),
+        }
+        .authzid()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LdapSaslStep {
+    // The server wants another round of the mechanism. Compute the response
+    // to these credentials, and send the message from continue_bind.
+    Continue(Option<Vec<u8>>),
+    // The bind succeeded, with the final credentials of the server, such as
+    // the server signature of SCRAM. Verify them as the mechanism requires,
+    // then send the message from whoami.
+    Bound(Option<Vec<u8>>),
+    Done(LdapSaslIdentity),
+}
+
+impl LdapSaslWhoami {
+    // Start the flow, returning the first bind to send.
+    pub fn new(msgid: i32, mechanism: &str, credentials: Option<Vec<u8>>) -> (Self, LdapMsg) {
+        let flow = LdapSaslWhoami {
+            mechanism: mechanism.to_string(),
+            msgid,
+            state: SaslWhoamiState::Binding,
+        };
+        let msg = flow.bind_msg(credentials);
+        (flow, msg)
+    }
+
+    pub fn mechanism(&self) -> &str {
+        &self.mechanism
+    }
+
+    fn bind_msg(&self, credentials: Option<Vec<u8>>) -> LdapMsg {
+        LdapMsg::new(
+            self.msgid,
+            LdapOp::BindRequest(LdapBindRequest::sasl("", &self.mechanism, credentials)),
+        )
+    }
+
+    // The next bind of the mechanism, after LdapSaslStep::Continue.
+    pub fn continue_bind(&mut self, msgid: i32, credentials: Option<Vec<u8>>) -> LdapMsg {
+        self.msgid = msgid;
+        self.bind_msg(credentials)
+    }
+
+    // The whoami to send, after LdapSaslStep::Bound.
+    pub fn whoami(&mut self, msgid: i32) -> LdapMsg {
+        self.msgid = msgid;
+        self.state = SaslWhoamiState::Whoami;
+        LdapMsg::new(msgid, LdapOp::ExtendedRequest(LdapWhoamiRequest {}.into()))
+    }
+
+    pub fn step(&mut self, msg: &LdapMsg) -> Result<LdapSaslStep, LdapError> {
+        let unexpected = || LdapError::new(LdapResultCode::ProtocolError, "unexpected response");
+        if msg.msgid != self.msgid {
+            return Err(unexpected());
+        }
+
+        match (self.state, &msg.op) {
+            (SaslWhoamiState::Binding, LdapOp::BindResponse(res)) => match res.res.code {
+                LdapResultCode::SaslBindInProgress => {
+                    Ok(LdapSaslStep::Continue(res.saslcreds.clone()))
+                }
+                LdapResultCode::Success => {
+                    self.state = SaslWhoamiState::Bound;
+                    Ok(LdapSaslStep::Bound(res.saslcreds.clone()))
+                }
+                _ => Err(res.res.clone().into()),
+            },
+            (SaslWhoamiState::Whoami, LdapOp::ExtendedResponse(res)) => {
+                if res.res.code != LdapResultCode::Success {
+                    return Err(res.res.clone().into());
+                }
+                let whoami = LdapWhoamiResponse::try_from(res).map_err(|_| unexpected())?;
+                self.state = SaslWhoamiState::Done;
+                Ok(LdapSaslStep::Done(LdapSaslIdentity {
+                    mechanism: self.mechanism.clone(),
+                    authzid: whoami.dn,
+                }))
+            }
+            _ => Err(unexpected()),
+        }
+    }
+}
@@ -0,0 +1,244 @@
+// Schema discovery. The RootDSE names the subschema entry, and reading that
+// entry returns the attributeTypes and objectClasses definitions, which are
+// parsed here into their commonly needed parts.
+// https://tools.ietf.org/html/rfc4512#section-4.1
+
+use crate::proto::{LdapPartialAttribute, LdapSearchResultEntry};
+use std::collections::BTreeMap;
+
+// The entry returned by a base search of "".
+#[derive(Debug, Clone, PartialEq)]
+pub struct RootDse {
+    pub entry: LdapSearchResultEntry,
+}
+
+impl From<LdapSearchResultEntry> for RootDse {
+    fn from(entry: LdapSearchResultEntry) -> Self {
+        RootDse { entry }
+    }
+}
+
+impl RootDse {
+    // The dn to read, with the attributes attributeTypes and objectClasses,
+    // to get the schema.
+    pub fn subschema_subentry(&self) -> Option<String> {
+        find_values(&self.entry, "subschemaSubentry")
+            .and_then(|vals| vals.first())
+            .and_then(|v| String::from_utf8(v.clone()).ok())
+    }
+}
+
+// https://tools.ietf.org/html/rfc4512#section-4.1.2
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttributeTypeDefinition {
+    pub oid: String,
+    pub names: Vec<String>,
+    pub sup: Option<String>,
+    // The syntax oid, without any length bound.
+    pub syntax: Option<String>,
+    pub single_value: bool,
+}
+
+impl AttributeTypeDefinition {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (oid, mut fields) = parse_definition(s)?;
+        Some(AttributeTypeDefinition {
+            oid,
+            names: fields.remove("NAME").unwrap_or_default(),
+            sup: fields.remove("SUP").and_then(|v| v.into_iter().next()),
+            syntax: fields
+                .remove("SYNTAX")
+                .and_then(|v| v.into_iter().next())
+                .map(|s| s.split('{').next().unwrap_or_default().to_string()),
+            single_value: fields.contains_key("SINGLE-VALUE"),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectClassKind {
+    Abstract,
+    Structural,
+    Auxiliary,
+}
+
+// https://tools.ietf.org/html/rfc4512#section-4.1.1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectClassDefinition {
+    pub oid: String,
+    pub names: Vec<String>,
+    pub sup: Vec<String>,
+    pub kind: ObjectClassKind,
+    pub must: Vec<String>,
+    pub may: Vec<String>,
+}
+
+impl ObjectClassDefinition {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (oid, mut fields) = parse_definition(s)?;
+        // Structural is the default when no kind is given.
+        let kind = if fields.contains_key("ABSTRACT") {
+            ObjectClassKind::Abstract
+        } else if fields.contains_key("AUXILIARY") {
+            ObjectClassKind::Auxiliary
+        } else {
+            ObjectClassKind::Structural
+        };
+        Some(ObjectClassDefinition {
+            oid,
+            names: fields.remove("NAME").unwrap_or_default(),
+            sup: fields.remove("SUP").unwrap_or_default(),
+            kind,
+            must: fields.remove("MUST").unwrap_or_default(),
+            may: fields.remove("MAY").unwrap_or_default(),
+        })
+    }
+}
+
+// The definitions held by a subschema entry. Definitions that can't be
+// parsed are skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Subschema {
+    pub attribute_types: Vec<AttributeTypeDefinition>,
+    pub object_classes: Vec<ObjectClassDefinition>,
+}
+
+impl From<&LdapSearchResultEntry> for Subschema {
+    fn from(entry: &LdapSearchResultEntry) -> Self {
+        fn parse_all<T>(
+            entry: &LdapSearchResultEntry,
+            atype: &str,
+            parse: fn(&str) -> Option<T>,
+        ) -> Vec<T> {
+            find_values(entry, atype)
+                .map(|vals| {
+                    vals.iter()
+                        .filter_map(|v| {
+                            let def = std::str::from_utf8(v).ok().and_then(parse);
+                            if def.is_none() {
+                                warn!(%atype, "skipping invalid schema definition");
+                            }
+                            def
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        }
+
+        Subschema {
+            attribute_types: parse_all(entry, "attributeTypes", AttributeTypeDefinition::parse),
+            object_classes: parse_all(entry, "objectClasses", ObjectClassDefinition::parse),
+        }
+    }
+}
+
+fn find_values<'a>(entry: &'a LdapSearchResultEntry, atype: &str) -> Option<&'a Vec<Vec<u8>>> {
+    entry
+        .attributes
+        .iter()
+        .find(|a| a.atype.eq_ignore_ascii_case(atype))
+        .map(|LdapPartialAttribute { vals, .. }| vals)
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Open,
+    Close,
+    Quoted(&'a str),
+    Word(&'a str),
+}
+
+fn tokenize(mut s: &str) -> Option<Vec<Token<'_>>> {
+    let mut tokens = Vec::new();
+    loop {
+        s = s.trim_start();
+        let token = match s.chars().next() {
+            None => return Some(tokens),
+            Some('(') => {
+                s = &s[1..];
+                Token::Open
+            }
+            Some(')') => {
+                s = &s[1..];
+                Token::Close
+            }
+            // oids in a list are separated by $, which we don't need.
+            Some('$') => {
+                s = &s[1..];
+                continue;
+            }
+            Some('\'') => {
+                let end = s[1..].find('\'')? + 1;
+                let quoted = &s[1..end];
+                s = &s[end + 1..];
+                Token::Quoted(quoted)
+            }
+            Some(_) => {
+                let end = s
+                    .find(|c: char| c.is_whitespace() || matches!(c, '(' | ')' | '\'' | '$'))
+                    .unwrap_or(s.len());
+                let word = &s[..end];
+                s = &s[end..];
+                Token::Word(word)
+            }
+        };
+        tokens.push(token);
+    }
+}
+
+// The fields that are present or absent, and have no value.
+const FLAGS: &[&str] = &[
+    "OBSOLETE",
+    "SINGLE-VALUE",
+    "COLLECTIVE",
+    "NO-USER-MODIFICATION",
+    "ABSTRACT",
+    "STRUCTURAL",
+    "AUXILIARY",
+];
+
+// Split a definition into its oid and fields, keyed by the uppercased field
+// name. Each field value is a list, as it may be a single value or a
+// parenthesised list of them.
+fn parse_definition(s: &str) -> Option<(String, BTreeMap<String, Vec<String>>)> {
+    let mut tokens = tokenize(s)?.into_iter();
+    if tokens.next()? != Token::Open {
+        return None;
+    }
+    let oid = match tokens.next()? {
+        Token::Word(oid) => oid.to_string(),
+        _ => return None,
+    };
+
+    let mut fields = BTreeMap::new();
+    loop {
+        let key = match tokens.next()? {
+            Token::Close => break,
+            Token::Word(key) => key.to_ascii_uppercase(),
+            _ => return None,
+        };
+        let values = if FLAGS.contains(&key.as_str()) {
+            Vec::new()
+        } else {
+            match tokens.next()? {
+                Token::Quoted(v) | Token::Word(v) => vec![v.to_string()],
+                Token::Open => {
+                    let mut values = Vec::new();
+                    loop {
+                        match tokens.next()? {
+                            Token::Close => break,
+                            Token::Quoted(v) | Token::Word(v) => values.push(v.to_string()),
+                            Token::Open => return None,
+                        }
+                    }
+                    values
+                }
+                Token::Close => return None,
+            }
+        };
+        fields.insert(key, values);
+    }
+
+    // Nothing may follow the closing parenthesis.
+    tokens.next().is_none().then_some((oid, fields))
+}
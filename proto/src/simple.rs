@@ -46,7 +46,7 @@ impl std::fmt::Debug for SimpleBindRequest {
         f.debug_struct("SimpleBindRequest")
             .field("msgid", &self.msgid)
             .field("dn", &self.dn)
-            .field("pw", &"********")
+            .field("pw", &REDACTED)
             .finish()
     }
 }
@@ -58,6 +58,7 @@ impl TryFrom<LdapMsg> for ServerOps {
         let LdapMsg { msgid, op, ctrl: _ } = value;
         match op {
             LdapOp::BindRequest(LdapBindRequest {
+                version: 3,
                 dn,
                 cred: LdapBindCred::Simple(pw),
             }) => Ok(ServerOps::SimpleBind(SimpleBindRequest { msgid, dn, pw })),
@@ -146,6 +147,19 @@ impl SearchRequest {
         }
     }
 
+    // If the filter is more complex than budget allows, this returns the
+    // response to reject the search with. See LdapFilter::complexity
+    pub fn reject_complex_filter(&self, budget: usize) -> Option<LdapMsg> {
+        if self.filter.complexity() > budget {
+            Some(self.gen_error(
+                LdapResultCode::UnwillingToPerform,
+                "filter too complex".to_string(),
+            ))
+        } else {
+            None
+        }
+    }
+
     pub fn gen_error(&self, rc: LdapResultCode, msg: String) -> LdapMsg {
         LdapMsg {
             msgid: self.msgid,
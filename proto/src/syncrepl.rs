@@ -0,0 +1,191 @@
+// The consumer side of an rfc4533 refresh. Feed each message of the sync
+// search to process, and once the refresh is complete, deletes gives the
+// entries the consumer must remove from its copy.
+// https://www.rfc-editor.org/rfc/rfc4533#section-3.4
+
+use crate::proto::*;
+use std::collections::BTreeSet;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncReplPhase {
+    // A refresh starts in the present phase, where the server sends the
+    // entries that are unchanged, or changed, since the cookie.
+    Present,
+    // The server sends the entries deleted since the cookie.
+    Delete,
+    // The refresh is complete, and changes are now sent as they happen.
+    Persist,
+    // The refresh is complete, and the search is done.
+    Done,
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncReplState {
+    phase: SyncReplPhase,
+    cookie: Option<Vec<u8>>,
+    // The entries the consumer held before the refresh.
+    known: BTreeSet<Uuid>,
+    present: BTreeSet<Uuid>,
+    deleted: BTreeSet<Uuid>,
+    // Set once the server starts a present phase, after which any known
+    // entry not reported present has been deleted.
+    present_phase: bool,
+}
+
+impl SyncReplState {
+    // known is the set of entries the consumer already holds from a previous
+    // sync with cookie, and is empty for an initial sync.
+    pub fn new<I>(known: I, cookie: Option<Vec<u8>>) -> Self
+    where
+        I: IntoIterator<Item = Uuid>,
+    {
+        SyncReplState {
+            phase: SyncReplPhase::Present,
+            cookie,
+            known: known.into_iter().collect(),
+            present: BTreeSet::new(),
+            deleted: BTreeSet::new(),
+            present_phase: false,
+        }
+    }
+
+    pub fn phase(&self) -> SyncReplPhase {
+        self.phase
+    }
+
+    // The latest cookie from the server, to resume from.
+    pub fn cookie(&self) -> Option<&[u8]> {
+        self.cookie.as_deref()
+    }
+
+    pub fn is_refresh_complete(&self) -> bool {
+        matches!(self.phase, SyncReplPhase::Persist | SyncReplPhase::Done)
+    }
+
+    // The entries to delete from the consumer. After a present phase, this
+    // is every known entry that was not reported as present, as well as
+    // those reported deleted in a following delete phase. Only complete once
+    // the refresh is.
+    pub fn deletes(&self) -> BTreeSet<Uuid> {
+        if !self.present_phase {
+            self.deleted.clone()
+        } else {
+            self.known
+                .difference(&self.present)
+                .chain(self.deleted.iter())
+                .copied()
+                .collect()
+        }
+    }
+
+    fn set_cookie(&mut self, cookie: &Option<Vec<u8>>) {
+        if let Some(c) = cookie {
+            self.cookie = Some(c.clone());
+        }
+    }
+
+    // The end of a phase. If done, the refresh is complete, and a
+    // refreshAndPersist search moves to persist. Otherwise a delete phase
+    // follows.
+    fn end_phase(&mut self, done: bool) {
+        self.phase = if done {
+            SyncReplPhase::Persist
+        } else {
+            SyncReplPhase::Delete
+        };
+    }
+
+    pub fn process(&mut self, msg: &LdapMsg) -> Result<(), LdapProtoError> {
+        if self.phase == SyncReplPhase::Done {
+            return Err(LdapProtoError::SyncStateInvalid);
+        }
+
+        match &msg.op {
+            LdapOp::SearchResultEntry(_) => {
+                let (state, entry_uuid, cookie) = msg
+                    .ctrl
+                    .iter()
+                    .find_map(|c| match c {
+                        LdapControl::SyncState {
+                            state,
+                            entry_uuid,
+                            cookie,
+                        } => Some((state, entry_uuid, cookie)),
+                        _ => None,
+                    })
+                    .ok_or(LdapProtoError::SyncStateInvalid)?;
+                self.set_cookie(cookie);
+                match state {
+                    SyncStateValue::Delete => {
+                        self.present.remove(entry_uuid);
+                        self.deleted.insert(*entry_uuid);
+                    }
+                    SyncStateValue::Present => {
+                        // Unchanged entries are only sent in a present phase.
+                        self.present_phase = true;
+                        self.deleted.remove(entry_uuid);
+                        self.present.insert(*entry_uuid);
+                    }
+                    SyncStateValue::Add | SyncStateValue::Modify => {
+                        self.deleted.remove(entry_uuid);
+                        self.present.insert(*entry_uuid);
+                    }
+                }
+            }
+            LdapOp::IntermediateResponse(ir) => match ir {
+                LdapIntermediateResponse::SyncInfoNewCookie { cookie } => {
+                    self.cookie = Some(cookie.clone());
+                }
+                LdapIntermediateResponse::SyncInfoRefreshPresent { cookie, done } => {
+                    self.set_cookie(cookie);
+                    self.present_phase = true;
+                    self.end_phase(*done);
+                }
+                LdapIntermediateResponse::SyncInfoRefreshDelete { cookie, done } => {
+                    self.set_cookie(cookie);
+                    self.end_phase(*done);
+                }
+                LdapIntermediateResponse::SyncInfoIdSet {
+                    cookie,
+                    refresh_deletes,
+                    syncuuids,
+                } => {
+                    self.set_cookie(cookie);
+                    if *refresh_deletes {
+                        self.deleted.extend(syncuuids.iter().copied());
+                    } else {
+                        self.present_phase = true;
+                        self.present.extend(syncuuids.iter().copied());
+                    }
+                }
+                LdapIntermediateResponse::Raw { .. } => {
+                    return Err(LdapProtoError::SyncStateInvalid)
+                }
+            },
+            LdapOp::SearchResultDone(res) if res.code == LdapResultCode::Success => {
+                let (cookie, refresh_deletes) = msg
+                    .ctrl
+                    .iter()
+                    .find_map(|c| match c {
+                        LdapControl::SyncDone {
+                            cookie,
+                            refresh_deletes,
+                        } => Some((cookie, *refresh_deletes)),
+                        _ => None,
+                    })
+                    .ok_or(LdapProtoError::SyncStateInvalid)?;
+                self.set_cookie(cookie);
+                // A refreshOnly search reports here which phase ended the
+                // refresh.
+                if !self.is_refresh_complete() && !refresh_deletes {
+                    self.present_phase = true;
+                }
+                self.phase = SyncReplPhase::Done;
+            }
+            _ => return Err(LdapProtoError::SyncStateInvalid),
+        }
+
+        Ok(())
+    }
+}